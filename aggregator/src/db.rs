@@ -0,0 +1,193 @@
+use sea_orm::{ConnectionTrait, DatabaseConnection, DatabaseTransaction, DbBackend, EntityTrait, QueryOrder, PaginatorTrait, Set, Statement, TransactionTrait, FromQueryResult};
+use shared::{CandleActiveModel, CandleEntity, CandleInterval, CandleModel, MatchedOrder, Trade, TradeColumn};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+// Trades read per page during the backfill
+const BACKFILL_PAGE_SIZE: u64 = 10_000;
+// Candles inserted per statement during the backfill
+const INSERT_BATCH_SIZE: usize = 1_000;
+
+#[derive(FromQueryResult)]
+struct CandleOffset {
+    partition: i32,
+    next_offset: i64,
+}
+
+pub struct CandleDB {
+    db: DatabaseConnection,
+}
+
+impl CandleDB {
+    pub async fn new(database_url: &str) -> anyhow::Result<Self> {
+        let db = sea_orm::Database::connect(database_url).await?;
+        Ok(Self { db })
+    }
+
+    /// The next `matched-orders` offset per partition, empty before the first backfill.
+    pub async fn load_offsets(&self) -> anyhow::Result<HashMap<i32, i64>> {
+        let offsets = CandleOffset::find_by_statement(Statement::from_string(
+            DbBackend::Postgres,
+            "SELECT partition, next_offset FROM candle_offsets",
+        ))
+        .all(&self.db)
+        .await?;
+
+        Ok(offsets.into_iter().map(|o| (o.partition, o.next_offset)).collect())
+    }
+
+    /// Builds the candles of every fill in `trades` and stores `offsets`, where consumption
+    /// continues, in the same transaction. Settlement must have applied every event before
+    /// `offsets` so that no fill is missed. The trade ids are recorded so the fills are not
+    /// counted again when consumed afterwards.
+    pub async fn backfill(&self, offsets: &HashMap<i32, i64>) -> anyhow::Result<usize> {
+        let txn = self.db.begin().await?;
+
+        let mut candles: Vec<CandleModel> = Vec::new();
+        // (pair, interval) -> candle being built
+        let mut open: HashMap<(String, CandleInterval), CandleModel> = HashMap::new();
+        let mut trade_count = 0;
+
+        let mut pages = Trade::find()
+            .order_by_asc(TradeColumn::Pair)
+            .order_by_asc(TradeColumn::CreatedAt)
+            .order_by_asc(TradeColumn::Id)
+            .paginate(&txn, BACKFILL_PAGE_SIZE);
+        while let Some(trades) = pages.fetch_and_next().await? {
+            let trade_ids: Vec<Uuid> = trades.iter().map(|trade| trade.id).collect();
+            for batch in trade_ids.chunks(INSERT_BATCH_SIZE) {
+                record_trades(&txn, batch).await?;
+            }
+            for trade in trades {
+                trade_count += 1;
+                for interval in CandleInterval::ALL {
+                    let open_time = interval.open_time(trade.created_at);
+                    let key = (trade.pair.clone(), interval);
+                    match open.get_mut(&key) {
+                        Some(candle) if candle.open_time == open_time => {
+                            candle.high = candle.high.max(trade.rate);
+                            candle.low = candle.low.min(trade.rate);
+                            candle.close = trade.rate;
+                            candle.volume += trade.amount;
+                        }
+                        _ => {
+                            let candle = CandleModel {
+                                pair: trade.pair.clone(),
+                                interval: interval.as_str().to_string(),
+                                open_time,
+                                open: trade.rate,
+                                high: trade.rate,
+                                low: trade.rate,
+                                close: trade.rate,
+                                volume: trade.amount,
+                            };
+                            if let Some(closed) = open.insert(key, candle) {
+                                candles.push(closed);
+                            }
+                        }
+                    }
+                }
+            }
+
+            if candles.len() >= INSERT_BATCH_SIZE {
+                insert_candles(&txn, std::mem::take(&mut candles)).await?;
+            }
+        }
+        candles.extend(open.into_values());
+        insert_candles(&txn, candles).await?;
+
+        for (&partition, &next_offset) in offsets {
+            store_offset(&txn, partition, next_offset).await?;
+        }
+
+        txn.commit().await?;
+        Ok(trade_count)
+    }
+
+    /// Adds a fill to its candle of every interval and records its trade id and the offset
+    /// following it, in one transaction. Fills whose trade id was already counted, by the
+    /// backfill or as an earlier delivery, only advance the offset.
+    pub async fn apply_fill(&self, matched: &MatchedOrder, partition: i32, offset: i64) -> anyhow::Result<()> {
+        let txn = self.db.begin().await?;
+
+        if record_trades(&txn, &[matched.trade_id]).await? > 0 {
+            for interval in CandleInterval::ALL {
+                // Fills of a pair arrive in order, so the newest one is the close
+                txn.execute(Statement::from_sql_and_values(
+                    DbBackend::Postgres,
+                    r#"INSERT INTO candles (pair, interval, open_time, open, high, low, close, volume)
+                       VALUES ($1, $2, $3, $4, $4, $4, $4, $5)
+                       ON CONFLICT (pair, interval, open_time) DO UPDATE SET
+                           high = GREATEST(candles.high, EXCLUDED.high),
+                           low = LEAST(candles.low, EXCLUDED.low),
+                           close = EXCLUDED.close,
+                           volume = candles.volume + EXCLUDED.volume"#,
+                    vec![
+                        matched.pair.clone().into(),
+                        interval.as_str().into(),
+                        interval.open_time(matched.created_at).into(),
+                        matched.rate.into(),
+                        matched.amount.into(),
+                    ],
+                ))
+                .await?;
+            }
+        }
+        store_offset(&txn, partition, offset + 1).await?;
+
+        txn.commit().await?;
+        Ok(())
+    }
+
+    /// Records the offset following an event that does not change any candle.
+    pub async fn skip_event(&self, partition: i32, offset: i64) -> anyhow::Result<()> {
+        let txn = self.db.begin().await?;
+        store_offset(&txn, partition, offset + 1).await?;
+        txn.commit().await?;
+        Ok(())
+    }
+}
+
+async fn insert_candles(txn: &DatabaseTransaction, candles: Vec<CandleModel>) -> anyhow::Result<()> {
+    for batch in candles.chunks(INSERT_BATCH_SIZE) {
+        let batch = batch.iter().cloned().map(|candle| CandleActiveModel {
+            pair: Set(candle.pair),
+            interval: Set(candle.interval),
+            open_time: Set(candle.open_time),
+            open: Set(candle.open),
+            high: Set(candle.high),
+            low: Set(candle.low),
+            close: Set(candle.close),
+            volume: Set(candle.volume),
+        });
+        CandleEntity::insert_many(batch).exec(txn).await?;
+    }
+    Ok(())
+}
+
+/// Records trade ids as counted and returns how many of them were new.
+async fn record_trades(txn: &DatabaseTransaction, trade_ids: &[Uuid]) -> anyhow::Result<u64> {
+    let placeholders: Vec<String> = (1..=trade_ids.len()).map(|i| format!("(${})", i)).collect();
+    let result = txn
+        .execute(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            format!(
+                "INSERT INTO candle_trades (trade_id) VALUES {} ON CONFLICT (trade_id) DO NOTHING",
+                placeholders.join(", ")
+            ),
+            trade_ids.iter().map(|&trade_id| trade_id.into()),
+        ))
+        .await?;
+    Ok(result.rows_affected())
+}
+
+async fn store_offset(txn: &DatabaseTransaction, partition: i32, next_offset: i64) -> anyhow::Result<()> {
+    txn.execute(Statement::from_sql_and_values(
+        DbBackend::Postgres,
+        r#"INSERT INTO candle_offsets (partition, next_offset) VALUES ($1, $2)
+           ON CONFLICT (partition) DO UPDATE SET next_offset = EXCLUDED.next_offset"#,
+        vec![partition.into(), next_offset.into()],
+    ))
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use rdkafka::{
+    config::ClientConfig,
+    consumer::{stream_consumer::StreamConsumer, Consumer},
+    Message, Offset, TopicPartitionList,
+};
+use shared::{kafka, MatcherEvent};
+use anyhow::Result;
+use std::collections::HashMap;
+
+/// A decoded event together with its position in the topic, stored with the candles it updates.
+pub struct ConsumedEvent {
+    pub event: MatcherEvent,
+    pub partition: i32,
+    pub offset: i64,
+}
+
+pub struct KafkaConsumer {
+    consumer: StreamConsumer,
+}
+
+impl KafkaConsumer {
+    /// Consumes every partition of `topic` from `offsets`, the positions stored with the
+    /// candles. Partitions without a stored offset are read from the beginning.
+    pub fn new(topic: &str, offsets: &HashMap<i32, i64>) -> Result<Self> {
+        let kafka_bootstrap_servers = std::env::var("KAFKA_BOOTSTRAP_SERVERS")
+            .map_err(|_| anyhow::anyhow!("KAFKA_BOOTSTRAP_SERVERS environment variable is required"))?;
+
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", &kafka_bootstrap_servers)
+            .set("group.id", "aggregator")
+            .set("enable.partition.eof", "false")
+            .set("session.timeout.ms", "6000")
+            // Offsets live in the `candle_offsets` table, never in Kafka
+            .set("enable.auto.commit", "false")
+            .create()?;
+
+        let mut assignment = TopicPartitionList::new();
+        for partition in kafka::partitions(&consumer, topic)? {
+            let offset = offsets
+                .get(&partition)
+                .map_or(Offset::Beginning, |&offset| Offset::Offset(offset));
+            assignment.add_partition_offset(topic, partition, offset)?;
+        }
+        consumer.assign(&assignment)?;
+
+        Ok(Self { consumer })
+    }
+
+    pub async fn consume_message(&self) -> Result<Option<ConsumedEvent>> {
+        match self.consumer.recv().await {
+            Ok(message) => {
+                let payload = message.payload().ok_or_else(|| anyhow::anyhow!("Empty payload"))?;
+                let event = MatcherEvent::from_json(std::str::from_utf8(payload)?)?;
+                Ok(Some(ConsumedEvent {
+                    event,
+                    partition: message.partition(),
+                    offset: message.offset(),
+                }))
+            }
+            Err(_e) => {
+                // Non-fatal errors (like timeout) are expected, return None
+                // Fatal errors will be propagated by the consumer automatically
+                Ok(None)
+            }
+        }
+    }
+}
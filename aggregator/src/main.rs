@@ -0,0 +1,74 @@
+mod db;
+mod kafka_consumer;
+
+use anyhow::Result;
+use db::CandleDB;
+use kafka_consumer::KafkaConsumer;
+use shared::{kafka, MatcherEvent};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Load environment variables from .env file
+    dotenv::dotenv().ok();
+
+    println!("Starting candle aggregator...");
+
+    // Initialize database
+    let database_url = std::env::var("DATABASE_URL")
+        .map_err(|_| anyhow::anyhow!("DATABASE_URL environment variable is required"))?;
+
+    let db = CandleDB::new(&database_url).await?;
+
+    // There are no fills before the matcher first publishes to `matched-orders`
+    kafka::wait_for_topic("matched-orders").await?;
+
+    // On first start, build the candles of past fills from `trades` and continue from the
+    // offsets settlement had reached
+    let mut offsets = db.load_offsets().await?;
+    if offsets.is_empty() {
+        offsets = kafka::high_watermarks("matched-orders")?;
+        kafka::wait_for_settlement("matched-orders", &offsets).await?;
+        let trade_count = db.backfill(&offsets).await?;
+        println!("Backfilled candles from {} trades", trade_count);
+    }
+
+    let consumer = KafkaConsumer::new("matched-orders", &offsets)?;
+
+    println!("Candle aggregator ready, consuming matcher events...");
+
+    // The offset of each event is stored with the candles it changes, so an event is counted
+    // exactly once across restarts. A failed event is retried until it is applied.
+    loop {
+        match consumer.consume_message().await {
+            Ok(Some(consumed)) => {
+                let mut retry_delay = tokio::time::Duration::from_millis(100);
+                loop {
+                    let result = match &consumed.event {
+                        MatcherEvent::Matched(matched) => {
+                            db.apply_fill(matched, consumed.partition, consumed.offset).await
+                        }
+                        MatcherEvent::Cancelled(_) | MatcherEvent::Decremented(_) => {
+                            db.skip_event(consumed.partition, consumed.offset).await
+                        }
+                    };
+                    match result {
+                        Ok(()) => break,
+                        Err(e) => {
+                            eprintln!("Error updating candles, retrying in {:?}: {}", retry_delay, e);
+                            tokio::time::sleep(retry_delay).await;
+                            retry_delay = (retry_delay * 2).min(tokio::time::Duration::from_secs(30));
+                        }
+                    }
+                }
+            }
+            Ok(None) => {
+                // No message, continue
+                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+            }
+            Err(e) => {
+                eprintln!("Error consuming message: {}", e);
+                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
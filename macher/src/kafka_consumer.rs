@@ -1,17 +1,28 @@
 use rdkafka::{
     config::ClientConfig,
-    consumer::{stream_consumer::StreamConsumer, Consumer},
-    Message,
+    consumer::{stream_consumer::StreamConsumer, CommitMode, Consumer},
+    Message, Offset, TopicPartitionList,
 };
-use shared::OrderMessage;
+use shared::{kafka, OrderCommand};
 use anyhow::Result;
+use std::collections::HashMap;
+
+/// A decoded command together with its position in the topic, committed once its events are sent.
+pub struct ConsumedCommand {
+    pub command: OrderCommand,
+    topic: String,
+    pub partition: i32,
+    pub offset: i64,
+}
 
 pub struct KafkaConsumer {
     consumer: StreamConsumer,
 }
 
 impl KafkaConsumer {
-    pub fn new(topic: &str) -> Result<Self> {
+    /// Consumes every partition of `topic`, starting from `offsets` where given (the positions
+    /// recorded in a snapshot) and from the group's committed offset elsewhere.
+    pub fn new(topic: &str, offsets: &HashMap<i32, i64>) -> Result<Self> {
         let kafka_bootstrap_servers = std::env::var("KAFKA_BOOTSTRAP_SERVERS")
             .map_err(|_| anyhow::anyhow!("KAFKA_BOOTSTRAP_SERVERS environment variable is required"))?;
         
@@ -20,21 +31,34 @@ impl KafkaConsumer {
             .set("group.id", "order-matcher")
             .set("enable.partition.eof", "false")
             .set("session.timeout.ms", "6000")
-            .set("enable.auto.commit", "true")
+            // Offsets are committed only after the command's events reach `matched-orders`
+            .set("enable.auto.commit", "false")
             .set("auto.offset.reset", "earliest")
             .create()?;
 
-        consumer.subscribe(&[topic])?;
+        let mut assignment = TopicPartitionList::new();
+        for partition in kafka::partitions(&consumer, topic)? {
+            let offset = offsets
+                .get(&partition)
+                .map_or(Offset::Stored, |&offset| Offset::Offset(offset));
+            assignment.add_partition_offset(topic, partition, offset)?;
+        }
+        consumer.assign(&assignment)?;
 
         Ok(Self { consumer })
     }
 
-    pub async fn consume_message(&self) -> Result<Option<OrderMessage>> {
+    pub async fn consume_message(&self) -> Result<Option<ConsumedCommand>> {
         match self.consumer.recv().await {
             Ok(message) => {
                 let payload = message.payload().ok_or_else(|| anyhow::anyhow!("Empty payload"))?;
-                let order_msg = OrderMessage::from_json(std::str::from_utf8(payload)?)?;
-                Ok(Some(order_msg))
+                let command = OrderCommand::from_json(std::str::from_utf8(payload)?)?;
+                Ok(Some(ConsumedCommand {
+                    command,
+                    topic: message.topic().to_string(),
+                    partition: message.partition(),
+                    offset: message.offset(),
+                }))
             }
             Err(_e) => {
                 // Non-fatal errors (like timeout) are expected, return None
@@ -43,5 +67,12 @@ impl KafkaConsumer {
             }
         }
     }
-}
 
+    /// Commits the offset following `consumed`, so it is not redelivered after a restart.
+    pub fn commit(&self, consumed: &ConsumedCommand) -> Result<()> {
+        let mut offsets = TopicPartitionList::new();
+        offsets.add_partition_offset(&consumed.topic, consumed.partition, Offset::Offset(consumed.offset + 1))?;
+        self.consumer.commit(&offsets, CommitMode::Sync)?;
+        Ok(())
+    }
+}
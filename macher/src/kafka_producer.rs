@@ -2,7 +2,7 @@ use rdkafka::{
     config::ClientConfig,
     producer::{FutureProducer, FutureRecord},
 };
-use shared::MatchedOrder;
+use shared::{MatcherEvent, OrderBookDelta, OrderBookSnapshot};
 use std::time::Duration;
 
 pub struct KafkaProducer {
@@ -22,21 +22,53 @@ impl KafkaProducer {
         Ok(Self { producer })
     }
 
-    pub async fn send_matched_order(&self, matched: MatchedOrder) -> anyhow::Result<()> {
-        let json = matched.to_json()?;
-        let key = format!("{}-{}", matched.buy_order_id, matched.sell_order_id);
+    pub async fn send_event(&self, event: MatcherEvent) -> anyhow::Result<()> {
+        let json = event.to_json()?;
+        // Use pair as key so fills and cancels of the same order reach settlement in order
+        let key = event.pair().to_string();
         let record = FutureRecord::to("matched-orders")
             .key(&key)
             .payload(&json);
 
         match self.producer.send(record, Duration::from_secs(0)).await {
             Ok(_) => {
-                println!("Sent matched order: buy={}, sell={}, amount={}", 
-                    matched.buy_order_id, matched.sell_order_id, matched.amount);
+                match &event {
+                    MatcherEvent::Matched(matched) => println!("Sent matched order: buy={}, sell={}, amount={}", 
+                        matched.buy_order_id, matched.sell_order_id, matched.amount),
+                    MatcherEvent::Cancelled(cancelled) => println!("Sent cancelled order: id={}, remaining={}", 
+                        cancelled.order_id, cancelled.remaining_amount),
+                    MatcherEvent::Decremented(decremented) => println!("Sent decremented order: id={}, amount={}, remaining={}",
+                        decremented.order_id, decremented.amount, decremented.remaining_amount),
+                }
                 Ok(())
             }
-            Err((e, _)) => Err(anyhow::anyhow!("Failed to send matched order: {}", e)),
+            Err((e, _)) => Err(anyhow::anyhow!("Failed to send matcher event: {}", e)),
+        }
+    }
+
+    /// Publishes changed price levels for the public market data stream.
+    pub async fn send_book_delta(&self, delta: &OrderBookDelta) -> anyhow::Result<()> {
+        let json = delta.to_json()?;
+        let record = FutureRecord::to("orderbook-deltas")
+            .key(&delta.pair)
+            .payload(&json);
+
+        match self.producer.send(record, Duration::from_secs(0)).await {
+            Ok(_) => Ok(()),
+            Err((e, _)) => Err(anyhow::anyhow!("Failed to send order book delta: {}", e)),
         }
     }
-}
 
+    /// Publishes a pair's book snapshot for the API server's order book endpoint.
+    pub async fn send_book_snapshot(&self, snapshot: &OrderBookSnapshot) -> anyhow::Result<()> {
+        let json = snapshot.to_json()?;
+        let record = FutureRecord::to("orderbook-snapshots")
+            .key(&snapshot.pair)
+            .payload(&json);
+
+        match self.producer.send(record, Duration::from_secs(0)).await {
+            Ok(_) => Ok(()),
+            Err((e, _)) => Err(anyhow::anyhow!("Failed to send order book snapshot: {}", e)),
+        }
+    }
+}
@@ -1,14 +1,34 @@
 mod matcher;
 mod kafka_consumer;
 mod kafka_producer;
+mod recovery;
+mod snapshot;
 
 use anyhow::Result;
-use matcher::OrderMatcher;
+use matcher::{OrderMatcher, SelfTradePrevention};
 use kafka_consumer::KafkaConsumer;
 use kafka_producer::KafkaProducer;
+use sea_orm::{DatabaseConnection, EntityTrait};
+use shared::{kafka, MatcherEvent, OrderCommand, OrderEntity, OrderMessage, PairRegistry};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
+const SNAPSHOT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+// Changed books are published for the API server at most this often
+const BOOK_SNAPSHOT_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+// Every book is republished this often, so a restarted API server fills its cache
+const BOOK_REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+// Price levels per side in published book snapshots
+const BOOK_SNAPSHOT_DEPTH: usize = 1000;
+
+/// Orders replayed from the database when no snapshot was available.
+struct Recovery {
+    recovered: HashSet<uuid::Uuid>,
+    recovered_at: chrono::DateTime<chrono::Utc>,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // Load environment variables from .env file
@@ -16,34 +36,96 @@ async fn main() -> Result<()> {
     
     println!("Starting order matcher...");
 
-    // Initialize matcher
-    let matcher = Arc::new(Mutex::new(OrderMatcher::new()));
+    // Load trading pairs, the matcher keeps one book per pair
+    let database_url = std::env::var("DATABASE_URL")
+        .map_err(|_| anyhow::anyhow!("DATABASE_URL environment variable is required"))?;
+    let db = sea_orm::Database::connect(&database_url).await?;
+    let pairs = PairRegistry::load(&db).await?;
 
-    // Initialize Kafka consumer
-    let consumer = KafkaConsumer::new("orders")?;
+    let snapshot_dir = PathBuf::from(
+        std::env::var("SNAPSHOT_DIR")
+            .map_err(|_| anyhow::anyhow!("SNAPSHOT_DIR environment variable is required"))?,
+    );
+    let self_trade_prevention: SelfTradePrevention = std::env::var("SELF_TRADE_PREVENTION")
+        .map_err(|_| anyhow::anyhow!("SELF_TRADE_PREVENTION environment variable is required"))?
+        .parse()
+        .map_err(anyhow::Error::msg)?;
 
     // Initialize Kafka producer for matched orders
     let producer = Arc::new(KafkaProducer::new().await?);
 
+    // Restore the books from the newest snapshot and replay the `orders` topic from its offsets,
+    // cancelling resting orders that are off their pair's current tick or lot size. Without a
+    // snapshot, rebuild the books from the open orders in the database.
+    let (matcher, offsets, recovery) = match snapshot::load_latest(&snapshot_dir, &pairs, self_trade_prevention)? {
+        Some(snapshot) => {
+            let mut matcher = snapshot.matcher;
+            publish(&producer, matcher.reject_off_size_orders()).await;
+            publish_book_deltas(&producer, &mut matcher).await;
+            (matcher, snapshot.offsets, None)
+        }
+        None => {
+            let (matcher, recovery) = rebuild_from_database(&db, pairs, self_trade_prevention, &producer).await?;
+            (matcher, HashMap::new(), Some(recovery))
+        }
+    };
+
+    let matcher = Arc::new(Mutex::new(matcher));
+
+    // Initialize Kafka consumer, `orders` appears with the first order command
+    kafka::wait_for_topic("orders").await?;
+    let consumer = KafkaConsumer::new("orders", &offsets)?;
+
     // Clone for async task
     let matcher_clone = matcher.clone();
     let producer_clone = producer.clone();
 
-    // Start consuming orders
-    tokio::spawn(async move {
+    // Start consuming orders. The task only ends when a command leaves the book in a state
+    // settlement cannot follow.
+    let consumer_task = tokio::spawn(async move {
+        let mut offsets = offsets;
+        let mut last_snapshot = std::time::Instant::now();
         loop {
             match consumer.consume_message().await {
-                Ok(Some(order_msg)) => {
-                    println!("Received order: {:?}", order_msg.order_id);
-                    
+                Ok(Some(consumed)) => {
                     let mut matcher_guard = matcher_clone.lock().await;
-                    let matched_orders = matcher_guard.match_order(order_msg).await;
+                    let events: Vec<MatcherEvent> = match consumed.command.clone() {
+                        OrderCommand::New(order_msg) => {
+                            println!("Received order: {:?}", order_msg.order_id);
+                            if is_redelivered(&db, recovery.as_ref(), &order_msg).await {
+                                println!("Order {} was handled before the restart, skipping", order_msg.order_id);
+                                Vec::new()
+                            } else {
+                                match matcher_guard.match_order(order_msg).await {
+                                    Ok(events) => events,
+                                    Err(e) => break Err(e),
+                                }
+                            }
+                        }
+                        OrderCommand::Cancel(cancel_msg) => {
+                            println!("Received cancel: {:?}", cancel_msg.order_id);
+                            matcher_guard
+                                .cancel_order(cancel_msg)
+                                .map(MatcherEvent::Cancelled)
+                                .into_iter()
+                                .collect()
+                        }
+                    };
                     
-                    // Send matched orders to Kafka
-                    for matched in matched_orders {
-                        if let Err(e) = producer_clone.send_matched_order(matched).await {
-                            eprintln!("Failed to send matched order: {}", e);
+                    // Send matcher events to Kafka before committing the command
+                    publish(&producer_clone, events).await;
+                    publish_book_deltas(&producer_clone, &mut matcher_guard).await;
+                    if let Err(e) = consumer.commit(&consumed) {
+                        eprintln!("Error committing offset: {}", e);
+                    }
+
+                    offsets.insert(consumed.partition, consumed.offset + 1);
+                    if last_snapshot.elapsed() >= SNAPSHOT_INTERVAL {
+                        match snapshot::save(&snapshot_dir, &matcher_guard, &offsets) {
+                            Ok(path) => println!("Saved snapshot {}", path.display()),
+                            Err(e) => eprintln!("Error saving snapshot: {}", e),
                         }
+                        last_snapshot = std::time::Instant::now();
                     }
                 }
                 Ok(None) => {
@@ -58,10 +140,120 @@ async fn main() -> Result<()> {
         }
     });
 
-    // Keep main thread alive
-    tokio::signal::ctrl_c().await?;
-    println!("Shutting down...");
+    // Publish book snapshots for the API server's order book endpoint
+    tokio::spawn(publish_book_snapshots(matcher.clone(), producer.clone()));
+
+    // Run until shutdown, or stop the process when matching fails
+    tokio::select! {
+        result = consumer_task => {
+            result??;
+        }
+        result = tokio::signal::ctrl_c() => {
+            result?;
+            println!("Shutting down...");
+        }
+    }
 
     Ok(())
 }
 
+/// Replays the open orders from the database once settlement has caught up, then checks the
+/// rebuilt books against the database.
+async fn rebuild_from_database(
+    db: &DatabaseConnection,
+    pairs: PairRegistry,
+    self_trade_prevention: SelfTradePrevention,
+    producer: &KafkaProducer,
+) -> Result<(OrderMatcher, Recovery)> {
+    let mut matcher = OrderMatcher::new(pairs, self_trade_prevention);
+
+    recovery::wait_for_settlement("matched-orders").await?;
+    let recovered_at = chrono::Utc::now();
+    let open_orders = recovery::load_open_orders(db).await?;
+    let mut recovered = HashSet::new();
+    for order in &open_orders {
+        recovered.insert(order.id);
+        let fill_index = recovery::next_fill_index(db, order).await?;
+        let events = matcher.replay_order(recovery::to_order_message(order), fill_index).await?;
+        publish(producer, events).await;
+    }
+    publish_book_deltas(producer, &mut matcher).await;
+    println!("Recovered {} open orders", recovered.len());
+
+    recovery::wait_for_settlement("matched-orders").await?;
+    let mismatches = recovery::check_consistency(db, &matcher, recovered_at).await?;
+    if mismatches > 0 {
+        eprintln!("Recovered books disagree with the database on {} orders", mismatches);
+    }
+
+    Ok((matcher, Recovery { recovered, recovered_at }))
+}
+
+/// Sends matcher events in order, retrying each until Kafka accepts it. The book has already
+/// changed, so dropping an event would leave settlement out of step with the matcher.
+async fn publish(producer: &KafkaProducer, events: Vec<MatcherEvent>) {
+    for event in events {
+        let mut retry_delay = tokio::time::Duration::from_millis(100);
+        while let Err(e) = producer.send_event(event.clone()).await {
+            eprintln!("Failed to send matcher event, retrying in {:?}: {}", retry_delay, e);
+            tokio::time::sleep(retry_delay).await;
+            retry_delay = (retry_delay * 2).min(tokio::time::Duration::from_secs(30));
+        }
+    }
+}
+
+/// Publishes the price levels changed by the last command. Market data is best effort, a lost
+/// delta is corrected by the next change at the same price.
+async fn publish_book_deltas(producer: &KafkaProducer, matcher: &mut OrderMatcher) {
+    for delta in matcher.take_book_deltas() {
+        if let Err(e) = producer.send_book_delta(&delta).await {
+            eprintln!("{}", e);
+        }
+    }
+}
+
+/// Publishes the books that changed since the last tick, and every book each
+/// `BOOK_REFRESH_INTERVAL`. Like deltas these are best effort, the next tick sends a fresh copy.
+async fn publish_book_snapshots(matcher: Arc<Mutex<OrderMatcher>>, producer: Arc<KafkaProducer>) {
+    let mut interval = tokio::time::interval(BOOK_SNAPSHOT_INTERVAL);
+    let mut last_refresh: Option<std::time::Instant> = None;
+    loop {
+        interval.tick().await;
+        let refresh = last_refresh.is_none_or(|at| at.elapsed() >= BOOK_REFRESH_INTERVAL);
+        if refresh {
+            last_refresh = Some(std::time::Instant::now());
+        }
+
+        let snapshots = matcher.lock().await.take_book_snapshots(refresh, BOOK_SNAPSHOT_DEPTH);
+        for snapshot in snapshots {
+            if let Err(e) = producer.send_book_snapshot(&snapshot).await {
+                eprintln!("{}", e);
+            }
+        }
+    }
+}
+
+/// Whether an order command was already accounted for by a database rebuild, either replayed or
+/// closed before the restart. Only orders created before the rebuild are checked. Books restored
+/// from a snapshot replay every command after the snapshot's offsets instead.
+async fn is_redelivered(db: &DatabaseConnection, recovery: Option<&Recovery>, order: &OrderMessage) -> bool {
+    let Some(recovery) = recovery else {
+        return false;
+    };
+    if recovery.recovered.contains(&order.order_id) {
+        return true;
+    }
+    if order.created_at > recovery.recovered_at {
+        return false;
+    }
+
+    // Orders inserted while the books were rebuilt are still pending and have to be matched
+    match OrderEntity::find_by_id(order.order_id).one(db).await {
+        Ok(Some(o)) => o.status != "pending",
+        Ok(None) => false,
+        Err(e) => {
+            eprintln!("Error looking up order {}: {}", order.order_id, e);
+            false
+        }
+    }
+}
@@ -1,164 +1,1269 @@
-use shared::{MatchedOrder, OrderMessage, OrderType};
+use shared::{
+    BookLevel, BookOrder, CancelOrderMessage, CancelReason, CancelledOrder, DecrementedOrder, MatchedOrder,
+    MatcherEvent, OrderBookDelta, OrderBookSnapshot, OrderMessage, OrderType, PairModel, PairRegistry,
+    PriceLevel, Side, TimeInForce,
+};
 use rust_decimal::Decimal;
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use chrono::Utc;
 
+use crate::snapshot::{self, Reader};
+
+/// Deterministic id of the `fill_index`-th fill or decrement of a taker order, so a replayed
+/// order yields the same trade ids and settlement can recognise fills it has already applied.
+pub(crate) fn trade_id(taker_order_id: uuid::Uuid, fill_index: usize) -> uuid::Uuid {
+    uuid::Uuid::new_v3(&taker_order_id, &(fill_index as u64).to_be_bytes())
+}
+
+/// Events produced while matching one incoming order, with the index of its next fill or
+/// decrement.
+struct MatchEvents {
+    events: Vec<MatcherEvent>,
+    fill_index: usize,
+}
+
+impl MatchEvents {
+    fn push(&mut self, event: MatcherEvent) {
+        self.events.push(event);
+    }
+
+    /// The id for the next fill or decrement of the taker order.
+    fn next_trade_id(&mut self, taker_order_id: uuid::Uuid) -> uuid::Uuid {
+        let id = trade_id(taker_order_id, self.fill_index);
+        self.fill_index += 1;
+        id
+    }
+}
+
+/// What the matcher does when an order would fill against a resting order of the same user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTradePrevention {
+    /// Cancel the remainder of the incoming order
+    CancelNewest,
+    /// Cancel the resting order and keep matching the incoming one
+    CancelOldest,
+    /// Cancel both orders
+    CancelBoth,
+    /// Take the overlapping amount off both orders and cancel whichever has nothing left
+    DecrementAndCancel,
+}
+
+impl std::str::FromStr for SelfTradePrevention {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cancel_newest" => Ok(SelfTradePrevention::CancelNewest),
+            "cancel_oldest" => Ok(SelfTradePrevention::CancelOldest),
+            "cancel_both" => Ok(SelfTradePrevention::CancelBoth),
+            "decrement_and_cancel" => Ok(SelfTradePrevention::DecrementAndCancel),
+            _ => Err(format!("Unknown self-trade prevention mode: {}", s)),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 struct OrderQueueEntry {
     order_id: uuid::Uuid,
-    #[allow(dead_code)]
     user_id: String,
+    client_order_id: Option<String>,
     amount: Decimal,
-    #[allow(dead_code)]
     created_at: chrono::DateTime<Utc>,
 }
 
-pub struct OrderMatcher {
+/// Order book of a single trading pair.
+#[derive(Default)]
+struct PairBook {
     // Price -> Queue of orders (sorted by time)
     bids: BTreeMap<Decimal, VecDeque<OrderQueueEntry>>, // Buy orders, sorted by price descending
     asks: BTreeMap<Decimal, VecDeque<OrderQueueEntry>>, // Sell orders, sorted by price ascending
+    // Order ID -> (side, price) of every resting order, used to locate cancels
+    resting: HashMap<uuid::Uuid, (OrderType, Decimal)>,
+    // Price levels changed since the last published delta
+    changed_bids: BTreeSet<Decimal>,
+    changed_asks: BTreeSet<Decimal>,
 }
 
-impl OrderMatcher {
-    pub fn new() -> Self {
-        Self {
-            bids: BTreeMap::new(),
-            asks: BTreeMap::new(),
+impl PairBook {
+    /// Removes a resting order from the book and returns its unfilled remainder.
+    /// Returns `None` if the order is no longer in the book (already filled or cancelled).
+    fn cancel_order(&mut self, cancel: CancelOrderMessage) -> Option<CancelledOrder> {
+        let (order_type, price) = self.resting.remove(&cancel.order_id)?;
+        let (book, changed) = if order_type.is_buy() {
+            (&mut self.bids, &mut self.changed_bids)
+        } else {
+            (&mut self.asks, &mut self.changed_asks)
+        };
+        changed.insert(price);
+
+        let queue = book.get_mut(&price)?;
+        let position = queue.iter().position(|entry| entry.order_id == cancel.order_id)?;
+        let entry = queue.remove(position)?;
+        if queue.is_empty() {
+            book.remove(&price);
+        }
+
+        Some(CancelledOrder {
+            order_id: entry.order_id,
+            pair: cancel.pair,
+            remaining_amount: entry.amount,
+            reason: CancelReason::UserRequested,
+            created_at: Utc::now(),
+        })
+    }
+
+    /// Removes the resting orders whose price or remaining amount fails `is_valid` and returns
+    /// their unfilled remainders.
+    fn remove_orders(
+        &mut self,
+        pair: &str,
+        is_valid: impl Fn(Decimal, Decimal) -> bool,
+    ) -> Vec<CancelledOrder> {
+        let mut removed = Vec::new();
+        for (book, changed) in [
+            (&mut self.bids, &mut self.changed_bids),
+            (&mut self.asks, &mut self.changed_asks),
+        ] {
+            for (&price, queue) in book.iter_mut() {
+                queue.retain(|entry| {
+                    if is_valid(price, entry.amount) {
+                        return true;
+                    }
+                    changed.insert(price);
+                    removed.push(CancelledOrder {
+                        order_id: entry.order_id,
+                        pair: pair.to_string(),
+                        remaining_amount: entry.amount,
+                        reason: CancelReason::Rejected,
+                        created_at: Utc::now(),
+                    });
+                    false
+                });
+            }
+            book.retain(|_, queue| !queue.is_empty());
+        }
+        for cancelled in &removed {
+            self.resting.remove(&cancelled.order_id);
+        }
+        removed
+    }
+
+    /// Returns true if the order would match against the best opposite price on arrival.
+    fn would_cross(&self, order: &OrderMessage) -> bool {
+        let best_opposite = if order.order_type.is_buy() {
+            self.asks.keys().next()
+        } else {
+            self.bids.keys().next_back()
+        };
+
+        match (best_opposite, order.rate) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(&price), Some(rate)) => {
+                if order.order_type.is_buy() {
+                    price <= rate
+                } else {
+                    price >= rate
+                }
+            }
         }
     }
 
-    pub async fn match_order(&mut self, order: OrderMessage) -> Vec<MatchedOrder> {
-        let mut matched_orders = Vec::new();
+    /// Why a fill-or-kill order cannot be filled completely on arrival, if it cannot. Every
+    /// self-trade prevention mode but `cancel_oldest` stops or shrinks the order at a resting
+    /// order of the same user, so reaching one before the order is filled rejects it.
+    fn fill_or_kill_rejection(
+        &self,
+        order: &OrderMessage,
+        pair: &PairModel,
+        self_trade_prevention: SelfTradePrevention,
+    ) -> Option<CancelReason> {
         let mut remaining_amount = order.amount;
 
-        match order.order_type {
-            OrderType::Buy => {
-                // Try to match buy order against asks (sell orders)
-                while remaining_amount > Decimal::ZERO {
-                    // Get best ask (lowest price)
-                    if let Some(mut entry) = self.asks.first_entry() {
-                        let best_ask_price = *entry.key();
-                        if best_ask_price <= order.rate {
-                            // Can match
-                            let ask_queue = entry.get_mut();
-                            if let Some(ask_order) = ask_queue.front_mut() {
-                                let match_amount = remaining_amount.min(ask_order.amount);
-                                
-                                // Create matched order
-                                let matched = MatchedOrder {
-                                    buy_order_id: order.order_id,
-                                    sell_order_id: ask_order.order_id,
-                                    pair: order.pair.clone(),
-                                    rate: best_ask_price,
-                                    amount: match_amount,
-                                    buy_fee: Decimal::ZERO, // No fee for MVC implementation
-                                    sell_fee: Decimal::ZERO,
-                                    created_at: Utc::now(),
-                                };
-                                matched_orders.push(matched);
-
-                                // Update amounts
-                                remaining_amount -= match_amount;
-                                ask_order.amount -= match_amount;
-
-                                // Remove order if fully filled
-                                if ask_order.amount <= Decimal::ZERO {
-                                    ask_queue.pop_front();
-                                    if ask_queue.is_empty() {
-                                        entry.remove();
-                                    }
-                                }
-                            } else {
-                                break;
-                            }
-                        } else {
-                            // Best ask is higher than buy price, can't match
-                            break;
+        if order.order_type.is_buy() {
+            for (&price, queue) in self.asks.iter() {
+                if order.rate.is_some_and(|rate| price > rate) {
+                    break;
+                }
+                for ask_order in queue {
+                    if ask_order.user_id == order.user_id {
+                        if self_trade_prevention == SelfTradePrevention::CancelOldest {
+                            continue;
+                        }
+                        return Some(CancelReason::SelfTrade);
+                    }
+                    if order.order_type == OrderType::MarketBuy {
+                        let match_amount = pair.round_to_lot(remaining_amount / price).min(ask_order.amount);
+                        // Only unspendable dust is left
+                        if match_amount <= Decimal::ZERO {
+                            return None;
                         }
+                        remaining_amount -= match_amount * price;
                     } else {
-                        // No asks available
-                        break;
+                        remaining_amount -= remaining_amount.min(ask_order.amount);
+                    }
+                    if remaining_amount <= Decimal::ZERO {
+                        return None;
                     }
                 }
+            }
+        } else {
+            for (&price, queue) in self.bids.iter().rev() {
+                if order.rate.is_some_and(|rate| price < rate) {
+                    break;
+                }
+                for bid_order in queue {
+                    if bid_order.user_id == order.user_id {
+                        if self_trade_prevention == SelfTradePrevention::CancelOldest {
+                            continue;
+                        }
+                        return Some(CancelReason::SelfTrade);
+                    }
+                    remaining_amount -= remaining_amount.min(bid_order.amount);
+                    if remaining_amount <= Decimal::ZERO {
+                        return None;
+                    }
+                }
+            }
+        }
 
-                // If there's remaining amount, add to bids
-                if remaining_amount > Decimal::ZERO {
-                    self.bids
-                        .entry(order.rate)
-                        .or_insert_with(VecDeque::new)
-                        .push_back(OrderQueueEntry {
-                            order_id: order.order_id,
-                            user_id: order.user_id,
-                            amount: remaining_amount,
-                            created_at: order.created_at,
-                        });
+        Some(CancelReason::FillOrKill)
+    }
+
+    /// Returns the new totals of the price levels changed since the last call, if any.
+    fn take_delta(&mut self, pair: &str) -> Option<OrderBookDelta> {
+        if self.changed_bids.is_empty() && self.changed_asks.is_empty() {
+            return None;
+        }
+
+        let levels = |book: &BTreeMap<Decimal, VecDeque<OrderQueueEntry>>, changed: BTreeSet<Decimal>| {
+            changed
+                .into_iter()
+                .map(|rate| PriceLevel {
+                    rate,
+                    amount: book
+                        .get(&rate)
+                        .map(|queue| queue.iter().map(|entry| entry.amount).sum())
+                        .unwrap_or(Decimal::ZERO),
+                })
+                .collect()
+        };
+
+        Some(OrderBookDelta {
+            pair: pair.to_string(),
+            bids: levels(&self.bids, std::mem::take(&mut self.changed_bids)),
+            asks: levels(&self.asks, std::mem::take(&mut self.changed_asks)),
+            created_at: Utc::now(),
+        })
+    }
+
+    /// The best `depth` price levels of each side with their orders.
+    fn book_snapshot(&self, pair: &str, depth: usize) -> OrderBookSnapshot {
+        let level = |(&rate, queue): (&Decimal, &VecDeque<OrderQueueEntry>)| BookLevel {
+            rate,
+            amount: queue.iter().map(|entry| entry.amount).sum(),
+            order_count: queue.len() as u32,
+            orders: queue
+                .iter()
+                .map(|entry| BookOrder {
+                    id: entry.order_id,
+                    amount: entry.amount,
+                })
+                .collect(),
+        };
+
+        OrderBookSnapshot {
+            pair: pair.to_string(),
+            bids: self.bids.iter().rev().take(depth).map(level).collect(),
+            asks: self.asks.iter().take(depth).map(level).collect(),
+            created_at: Utc::now(),
+        }
+    }
+
+    /// Appends both sides of the book to a snapshot payload, best price first and in time
+    /// priority within each price.
+    fn encode(&self, buf: &mut Vec<u8>) {
+        for side in [
+            self.bids.iter().rev().collect::<Vec<_>>(),
+            self.asks.iter().collect::<Vec<_>>(),
+        ] {
+            snapshot::put_u32(buf, side.len() as u32);
+            for (&price, queue) in side {
+                snapshot::put_decimal(buf, price);
+                snapshot::put_u32(buf, queue.len() as u32);
+                for entry in queue {
+                    snapshot::put_uuid(buf, entry.order_id);
+                    snapshot::put_str(buf, &entry.user_id);
+                    snapshot::put_opt_str(buf, entry.client_order_id.as_deref());
+                    snapshot::put_decimal(buf, entry.amount);
+                    snapshot::put_datetime(buf, entry.created_at);
                 }
             }
-            OrderType::Sell => {
-                // Try to match sell order against bids (buy orders)
-                while remaining_amount > Decimal::ZERO {
-                    // Get best bid (highest price)
-                    if let Some(mut entry) = self.bids.last_entry() {
-                        let best_bid_price = *entry.key();
-                        if best_bid_price >= order.rate {
-                            // Can match
-                            let bid_queue = entry.get_mut();
-                            if let Some(bid_order) = bid_queue.front_mut() {
-                                let match_amount = remaining_amount.min(bid_order.amount);
-                                
-                                // Create matched order
-                                let matched = MatchedOrder {
-                                    buy_order_id: bid_order.order_id,
-                                    sell_order_id: order.order_id,
-                                    pair: order.pair.clone(),
-                                    rate: best_bid_price,
-                                    amount: match_amount,
-                                    buy_fee: Decimal::ZERO, // No fee for MVC implementation
-                                    sell_fee: Decimal::ZERO,
-                                    created_at: Utc::now(),
-                                };
-                                matched_orders.push(matched);
-
-                                // Update amounts
-                                remaining_amount -= match_amount;
-                                bid_order.amount -= match_amount;
-
-                                // Remove order if fully filled
-                                if bid_order.amount <= Decimal::ZERO {
-                                    bid_queue.pop_front();
-                                    if bid_queue.is_empty() {
-                                        entry.remove();
-                                    }
-                                }
-                            } else {
-                                break;
-                            }
-                        } else {
-                            // Best bid is lower than sell price, can't match
-                            break;
-                        }
-                    } else {
-                        // No bids available
-                        break;
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> anyhow::Result<Self> {
+        let mut book = Self::default();
+        for order_type in [OrderType::Buy, OrderType::Sell] {
+            for _ in 0..reader.u32()? {
+                let price = reader.decimal()?;
+                let mut queue = VecDeque::new();
+                for _ in 0..reader.u32()? {
+                    let entry = OrderQueueEntry {
+                        order_id: reader.uuid()?,
+                        user_id: reader.str()?,
+                        client_order_id: reader.opt_str()?,
+                        amount: reader.decimal()?,
+                        created_at: reader.datetime()?,
+                    };
+                    book.resting.insert(entry.order_id, (order_type.clone(), price));
+                    queue.push_back(entry);
+                }
+                let side = if order_type.is_buy() { &mut book.bids } else { &mut book.asks };
+                side.insert(price, queue);
+            }
+        }
+
+        Ok(book)
+    }
+
+    /// Resolves a would-be self-trade between the incoming order and the first resting order at
+    /// `price`, which belongs to the same user, and returns the incoming order's new remaining
+    /// amount. Every amount taken off either order is reported to settlement as a cancellation
+    /// or a decrement so the locked funds are released.
+    fn prevent_self_trade(
+        &mut self,
+        order: &OrderMessage,
+        remaining_amount: Decimal,
+        price: Decimal,
+        pair: &PairModel,
+        mode: SelfTradePrevention,
+        events: &mut MatchEvents,
+    ) -> Decimal {
+        let (book, changed) = if order.order_type.is_buy() {
+            (&mut self.asks, &mut self.changed_asks)
+        } else {
+            (&mut self.bids, &mut self.changed_bids)
+        };
+        let Some(queue) = book.get_mut(&price) else {
+            return remaining_amount;
+        };
+        let Some(resting) = queue.front_mut() else {
+            return remaining_amount;
+        };
+
+        // Base amount both orders could have traded, for market buys the affordable whole lots
+        let overlap = if order.order_type == OrderType::MarketBuy {
+            pair.round_to_lot(remaining_amount / price)
+        } else {
+            remaining_amount
+        }
+        .min(resting.amount);
+
+        let resting_decrement = match mode {
+            SelfTradePrevention::CancelNewest => Decimal::ZERO,
+            SelfTradePrevention::CancelOldest | SelfTradePrevention::CancelBoth => resting.amount,
+            SelfTradePrevention::DecrementAndCancel => overlap,
+        };
+        // Market buys are decremented in quote currency, and a market buy that cannot afford a
+        // single lot only holds dust, which is cancelled
+        let taker_decrement = match mode {
+            SelfTradePrevention::CancelNewest | SelfTradePrevention::CancelBoth => remaining_amount,
+            SelfTradePrevention::CancelOldest => Decimal::ZERO,
+            SelfTradePrevention::DecrementAndCancel if overlap <= Decimal::ZERO => remaining_amount,
+            SelfTradePrevention::DecrementAndCancel if order.order_type == OrderType::MarketBuy => {
+                overlap * price
+            }
+            SelfTradePrevention::DecrementAndCancel => overlap,
+        };
+
+        if resting_decrement > Decimal::ZERO {
+            changed.insert(price);
+            if resting_decrement >= resting.amount {
+                let order_id = resting.order_id;
+                let amount = resting.amount;
+                self.resting.remove(&order_id);
+                queue.pop_front();
+                if queue.is_empty() {
+                    book.remove(&price);
+                }
+                events.push(MatcherEvent::Cancelled(CancelledOrder {
+                    order_id,
+                    pair: order.pair.clone(),
+                    remaining_amount: amount,
+                    reason: CancelReason::SelfTrade,
+                    created_at: Utc::now(),
+                }));
+            } else {
+                resting.amount -= resting_decrement;
+                let id = events.next_trade_id(order.order_id);
+                events.push(MatcherEvent::Decremented(DecrementedOrder {
+                    id,
+                    order_id: resting.order_id,
+                    pair: order.pair.clone(),
+                    amount: resting_decrement,
+                    remaining_amount: resting.amount,
+                    created_at: Utc::now(),
+                }));
+            }
+        }
+
+        if taker_decrement >= remaining_amount {
+            events.push(MatcherEvent::Cancelled(CancelledOrder {
+                order_id: order.order_id,
+                pair: order.pair.clone(),
+                remaining_amount,
+                reason: CancelReason::SelfTrade,
+                created_at: Utc::now(),
+            }));
+            return Decimal::ZERO;
+        }
+        if taker_decrement > Decimal::ZERO {
+            let id = events.next_trade_id(order.order_id);
+            events.push(MatcherEvent::Decremented(DecrementedOrder {
+                id,
+                order_id: order.order_id,
+                pair: order.pair.clone(),
+                amount: taker_decrement,
+                remaining_amount: remaining_amount - taker_decrement,
+                created_at: Utc::now(),
+            }));
+        }
+        remaining_amount - taker_decrement
+    }
+
+    /// Matches an incoming order against the opposite side of the book.
+    /// Good-til-cancelled and post-only limit orders rest their remainder in the book. Market,
+    /// immediate-or-cancel and rejected orders report their unfilled remainder as a cancellation
+    /// so settlement can release the locked funds. Trade ids continue from `first_fill_index`,
+    /// the number of fills and decrements the order already had.
+    fn match_order(
+        &mut self,
+        order: OrderMessage,
+        pair: &PairModel,
+        self_trade_prevention: SelfTradePrevention,
+        first_fill_index: usize,
+    ) -> Vec<MatcherEvent> {
+        let mut events = MatchEvents { events: Vec::new(), fill_index: first_fill_index };
+        // For market buys this is the quote currency amount left to spend
+        let mut remaining_amount = order.amount;
+
+        let rejection = match order.time_in_force {
+            TimeInForce::PostOnly if self.would_cross(&order) => Some(CancelReason::PostOnly),
+            TimeInForce::FillOrKill => self.fill_or_kill_rejection(&order, pair, self_trade_prevention),
+            _ => None,
+        };
+        if let Some(reason) = rejection {
+            events.push(MatcherEvent::Cancelled(CancelledOrder {
+                order_id: order.order_id,
+                pair: order.pair,
+                remaining_amount,
+                reason,
+                created_at: Utc::now(),
+            }));
+            return events.events;
+        }
+
+        if order.order_type.is_buy() {
+            // Try to match buy order against asks (sell orders)
+            while remaining_amount > Decimal::ZERO {
+                // Get best ask (lowest price)
+                let Some(mut entry) = self.asks.first_entry() else {
+                    // No asks available
+                    break;
+                };
+                let best_ask_price = *entry.key();
+                if order.rate.is_some_and(|rate| best_ask_price > rate) {
+                    // Best ask is higher than buy price, can't match
+                    break;
+                }
+
+                let ask_queue = entry.get_mut();
+                let Some(ask_order) = ask_queue.front_mut() else {
+                    break;
+                };
+                if ask_order.user_id == order.user_id {
+                    remaining_amount = self.prevent_self_trade(
+                        &order,
+                        remaining_amount,
+                        best_ask_price,
+                        pair,
+                        self_trade_prevention,
+                        &mut events,
+                    );
+                    continue;
+                }
+                let match_amount = if order.order_type == OrderType::MarketBuy {
+                    // Buy as many lots as the remaining quote amount affords at this price
+                    pair.round_to_lot(remaining_amount / best_ask_price).min(ask_order.amount)
+                } else {
+                    remaining_amount.min(ask_order.amount)
+                };
+                if match_amount <= Decimal::ZERO {
+                    break;
+                }
+
+                // Create matched order
+                let trade_id = events.next_trade_id(order.order_id);
+                events.push(MatcherEvent::Matched(MatchedOrder {
+                    trade_id,
+                    buy_order_id: order.order_id,
+                    sell_order_id: ask_order.order_id,
+                    taker_side: Side::Buy,
+                    pair: order.pair.clone(),
+                    rate: best_ask_price,
+                    amount: match_amount,
+                    buy_client_order_id: order.client_order_id.clone(),
+                    sell_client_order_id: ask_order.client_order_id.clone(),
+                    created_at: Utc::now(),
+                }));
+
+                // Update amounts
+                remaining_amount -= if order.order_type == OrderType::MarketBuy {
+                    match_amount * best_ask_price
+                } else {
+                    match_amount
+                };
+                ask_order.amount -= match_amount;
+                self.changed_asks.insert(best_ask_price);
+
+                // Remove order if fully filled
+                if ask_order.amount <= Decimal::ZERO {
+                    self.resting.remove(&ask_order.order_id);
+                    ask_queue.pop_front();
+                    if ask_queue.is_empty() {
+                        entry.remove();
+                    }
+                }
+            }
+        } else {
+            // Try to match sell order against bids (buy orders)
+            while remaining_amount > Decimal::ZERO {
+                // Get best bid (highest price)
+                let Some(mut entry) = self.bids.last_entry() else {
+                    // No bids available
+                    break;
+                };
+                let best_bid_price = *entry.key();
+                if order.rate.is_some_and(|rate| best_bid_price < rate) {
+                    // Best bid is lower than sell price, can't match
+                    break;
+                }
+
+                let bid_queue = entry.get_mut();
+                let Some(bid_order) = bid_queue.front_mut() else {
+                    break;
+                };
+                if bid_order.user_id == order.user_id {
+                    remaining_amount = self.prevent_self_trade(
+                        &order,
+                        remaining_amount,
+                        best_bid_price,
+                        pair,
+                        self_trade_prevention,
+                        &mut events,
+                    );
+                    continue;
+                }
+                let match_amount = remaining_amount.min(bid_order.amount);
+
+                // Create matched order
+                let trade_id = events.next_trade_id(order.order_id);
+                events.push(MatcherEvent::Matched(MatchedOrder {
+                    trade_id,
+                    buy_order_id: bid_order.order_id,
+                    sell_order_id: order.order_id,
+                    taker_side: Side::Sell,
+                    pair: order.pair.clone(),
+                    rate: best_bid_price,
+                    amount: match_amount,
+                    buy_client_order_id: bid_order.client_order_id.clone(),
+                    sell_client_order_id: order.client_order_id.clone(),
+                    created_at: Utc::now(),
+                }));
+
+                // Update amounts
+                remaining_amount -= match_amount;
+                bid_order.amount -= match_amount;
+                self.changed_bids.insert(best_bid_price);
+
+                // Remove order if fully filled
+                if bid_order.amount <= Decimal::ZERO {
+                    self.resting.remove(&bid_order.order_id);
+                    bid_queue.pop_front();
+                    if bid_queue.is_empty() {
+                        entry.remove();
                     }
                 }
+            }
+        }
 
-                // If there's remaining amount, add to asks
-                if remaining_amount > Decimal::ZERO {
-                    self.asks
-                        .entry(order.rate)
-                        .or_insert_with(VecDeque::new)
+        if remaining_amount > Decimal::ZERO {
+            let rests = !order.order_type.is_market()
+                && matches!(order.time_in_force, TimeInForce::GoodTilCancelled | TimeInForce::PostOnly);
+            match order.rate {
+                // If there's remaining amount on a resting limit order, add it to the book
+                Some(rate) if rests => {
+                    self.resting.insert(order.order_id, (order.order_type.clone(), rate));
+                    let (book, changed) = if order.order_type.is_buy() {
+                        (&mut self.bids, &mut self.changed_bids)
+                    } else {
+                        (&mut self.asks, &mut self.changed_asks)
+                    };
+                    changed.insert(rate);
+                    book.entry(rate)
+                        .or_default()
                         .push_back(OrderQueueEntry {
                             order_id: order.order_id,
                             user_id: order.user_id,
+                            client_order_id: order.client_order_id,
                             amount: remaining_amount,
                             created_at: order.created_at,
                         });
                 }
+                // Otherwise report the remainder back to settlement
+                _ => {
+                    let reason = if order.order_type.is_market() {
+                        CancelReason::Unfilled
+                    } else {
+                        CancelReason::Expired
+                    };
+                    events.push(MatcherEvent::Cancelled(CancelledOrder {
+                        order_id: order.order_id,
+                        pair: order.pair,
+                        remaining_amount,
+                        reason,
+                        created_at: Utc::now(),
+                    }));
+                }
+            }
+        }
+
+        events.events
+    }
+}
+
+// Number of recently matched order ids remembered to drop duplicate commands
+const RECENT_ORDERS: usize = 100_000;
+
+pub struct OrderMatcher {
+    pairs: PairRegistry,
+    self_trade_prevention: SelfTradePrevention,
+    // Pair -> order book, orders of different pairs never match each other
+    books: HashMap<String, PairBook>,
+    // Ids of the most recently matched orders, oldest first. The outbox relay delivers commands
+    // at least once, so the same order can arrive twice.
+    recent_orders: VecDeque<uuid::Uuid>,
+    recent_order_ids: HashSet<uuid::Uuid>,
+    // Pairs whose book changed since their last published book snapshot
+    stale_snapshots: HashSet<String>,
+}
+
+impl OrderMatcher {
+    pub fn new(pairs: PairRegistry, self_trade_prevention: SelfTradePrevention) -> Self {
+        let books = pairs
+            .iter()
+            .map(|p| (p.pair.clone(), PairBook::default()))
+            .collect();
+
+        Self {
+            pairs,
+            self_trade_prevention,
+            books,
+            recent_orders: VecDeque::new(),
+            recent_order_ids: HashSet::new(),
+            stale_snapshots: HashSet::new(),
+        }
+    }
+
+    /// Records an order id, returning `false` if it was matched recently.
+    fn remember_order(&mut self, order_id: uuid::Uuid) -> bool {
+        if !self.recent_order_ids.insert(order_id) {
+            return false;
+        }
+        self.recent_orders.push_back(order_id);
+        if self.recent_orders.len() > RECENT_ORDERS {
+            if let Some(oldest) = self.recent_orders.pop_front() {
+                self.recent_order_ids.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Appends every pair's book and the recently matched order ids to a snapshot payload.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let mut pairs: Vec<_> = self.books.keys().collect();
+        pairs.sort();
+        snapshot::put_u32(buf, pairs.len() as u32);
+        for pair in pairs {
+            snapshot::put_str(buf, pair);
+            self.books[pair].encode(buf);
+        }
+
+        snapshot::put_u32(buf, self.recent_orders.len() as u32);
+        for &order_id in &self.recent_orders {
+            snapshot::put_uuid(buf, order_id);
+        }
+    }
+
+    /// Restores the state written by `encode`. Books of pairs listed since the snapshot start empty.
+    pub fn decode(
+        pairs: PairRegistry,
+        self_trade_prevention: SelfTradePrevention,
+        reader: &mut Reader,
+    ) -> anyhow::Result<Self> {
+        let mut matcher = Self::new(pairs, self_trade_prevention);
+        for _ in 0..reader.u32()? {
+            let pair = reader.str()?;
+            let book = PairBook::decode(reader)?;
+            matcher.books.insert(pair, book);
+        }
+        for _ in 0..reader.u32()? {
+            matcher.remember_order(reader.uuid()?);
+        }
+
+        Ok(matcher)
+    }
+
+    /// Price level changes of every book since the last call, to publish after each command.
+    pub fn take_book_deltas(&mut self) -> Vec<OrderBookDelta> {
+        let deltas: Vec<OrderBookDelta> = self
+            .books
+            .iter_mut()
+            .filter_map(|(pair, book)| book.take_delta(pair))
+            .collect();
+        self.stale_snapshots
+            .extend(deltas.iter().map(|delta| delta.pair.clone()));
+        deltas
+    }
+
+    /// Book snapshots of the pairs that changed since the last call, or of every pair when `all`
+    /// is set, limited to the best `depth` price levels per side.
+    pub fn take_book_snapshots(&mut self, all: bool, depth: usize) -> Vec<OrderBookSnapshot> {
+        let stale = std::mem::take(&mut self.stale_snapshots);
+        self.books
+            .iter()
+            .filter(|(pair, _)| all || stale.contains(*pair))
+            .map(|(pair, book)| book.book_snapshot(pair, depth))
+            .collect()
+    }
+
+    /// Every resting order with its unfilled amount, across all pairs.
+    pub fn resting_orders(&self) -> impl Iterator<Item = (uuid::Uuid, Decimal)> + '_ {
+        self.books.values().flat_map(|book| {
+            book.bids
+                .values()
+                .chain(book.asks.values())
+                .flatten()
+                .map(|entry| (entry.order_id, entry.amount))
+        })
+    }
+
+    /// Cancels the resting orders that are off their pair's current tick or lot size. Books
+    /// restored from a snapshot keep the sizes of their time, so this runs after every restore
+    /// in case a pair's sizes changed since.
+    pub fn reject_off_size_orders(&mut self) -> Vec<MatcherEvent> {
+        let mut events = Vec::new();
+        for (pair, book) in self.books.iter_mut() {
+            let Some(pair_model) = self.pairs.get(pair) else {
+                continue;
+            };
+            for cancelled in book.remove_orders(pair, |rate, amount| {
+                pair_model.is_valid_rate(rate) && pair_model.is_valid_amount(amount)
+            }) {
+                eprintln!(
+                    "Rejecting resting order {} of {} off the tick or lot size of {}",
+                    cancelled.order_id, cancelled.remaining_amount, pair
+                );
+                events.push(MatcherEvent::Cancelled(cancelled));
+            }
+        }
+        events
+    }
+
+    /// Removes a resting order from its pair's book and returns its unfilled remainder.
+    pub fn cancel_order(&mut self, cancel: CancelOrderMessage) -> Option<CancelledOrder> {
+        self.books.get_mut(&cancel.pair)?.cancel_order(cancel)
+    }
+
+    /// Matches an order in its pair's book. Orders for unknown or suspended pairs and orders
+    /// off the pair's tick or lot size are rejected. Orders never fill against orders of the
+    /// same user, the configured self-trade prevention mode resolves them instead. A command
+    /// for an order that was matched recently is a duplicate delivery and is ignored.
+    ///
+    /// Fails if a fill is off the pair's tick or lot size, which settlement cannot follow. The
+    /// book has already changed by then, so the matcher must stop before publishing anything.
+    pub async fn match_order(&mut self, order: OrderMessage) -> anyhow::Result<Vec<MatcherEvent>> {
+        self.replay_order(order, 0).await
+    }
+
+    /// Matches an open order replayed from the database like [`Self::match_order`]. Its trade
+    /// ids continue after the `first_fill_index` fills and decrements settlement has already
+    /// stored for it, so new fills are not mistaken for ones it has applied.
+    pub async fn replay_order(
+        &mut self,
+        order: OrderMessage,
+        first_fill_index: usize,
+    ) -> anyhow::Result<Vec<MatcherEvent>> {
+        if !self.remember_order(order.order_id) {
+            println!("Ignoring duplicate order {}", order.order_id);
+            return Ok(Vec::new());
+        }
+
+        let Some(pair) = self.pairs.active(&order.pair) else {
+            return Ok(vec![reject(order)]);
+        };
+        // The API server validates orders, the book must only ever hold whole ticks and lots
+        let valid_size = match order.order_type {
+            OrderType::MarketBuy => order.amount > Decimal::ZERO,
+            OrderType::MarketSell => pair.is_valid_amount(order.amount),
+            OrderType::Buy | OrderType::Sell => {
+                order.rate.is_some_and(|rate| pair.is_valid_rate(rate)) && pair.is_valid_amount(order.amount)
             }
+        };
+        if !valid_size {
+            eprintln!("Rejecting order {} off the tick or lot size of {}", order.order_id, order.pair);
+            return Ok(vec![reject(order)]);
         }
 
-        matched_orders
+        let order_id = order.order_id;
+        let events = self
+            .books
+            .entry(order.pair.clone())
+            .or_default()
+            .match_order(order, pair, self.self_trade_prevention, first_fill_index);
+
+        // Neither the incoming order nor the resting ones can be off size at this point
+        for event in &events {
+            if let MatcherEvent::Matched(matched) = event {
+                if !(pair.is_valid_rate(matched.rate) && pair.is_valid_amount(matched.amount)) {
+                    anyhow::bail!(
+                        "fill {} of order {} for {} at {} is off the tick or lot size of {}",
+                        matched.trade_id,
+                        order_id,
+                        matched.amount,
+                        matched.rate,
+                        matched.pair
+                    );
+                }
+            }
+        }
+        Ok(events)
     }
 }
 
+/// Cancels an order the matcher refuses to match, releasing its locked funds.
+fn reject(order: OrderMessage) -> MatcherEvent {
+    MatcherEvent::Cancelled(CancelledOrder {
+        order_id: order.order_id,
+        pair: order.pair,
+        remaining_amount: order.amount,
+        reason: CancelReason::Rejected,
+        created_at: Utc::now(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: &str = "alice";
+    const BOB: &str = "bob";
+
+    fn d(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    fn pair() -> PairModel {
+        PairModel {
+            pair: "btc_jpy".to_string(),
+            base_currency: "BTC".to_string(),
+            quote_currency: "JPY".to_string(),
+            tick_size: d("1"),
+            lot_size: d("0.001"),
+            min_notional: d("0"),
+            min_amount: d("0"),
+            status: "active".to_string(),
+            created_at: Utc::now(),
+        }
+    }
+
+    fn order(
+        user_id: &str,
+        order_type: OrderType,
+        rate: Option<&str>,
+        amount: &str,
+        time_in_force: TimeInForce,
+    ) -> OrderMessage {
+        OrderMessage {
+            order_id: uuid::Uuid::new_v4(),
+            user_id: user_id.to_string(),
+            pair: "btc_jpy".to_string(),
+            order_type,
+            rate: rate.map(d),
+            amount: d(amount),
+            time_in_force,
+            client_order_id: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    /// Rests a limit order and returns its id.
+    fn rest(
+        book: &mut PairBook,
+        user_id: &str,
+        order_type: OrderType,
+        rate: &str,
+        amount: &str,
+    ) -> uuid::Uuid {
+        let order = order(user_id, order_type, Some(rate), amount, TimeInForce::GoodTilCancelled);
+        let order_id = order.order_id;
+        assert!(book.match_order(order, &pair(), SelfTradePrevention::CancelNewest, 0).is_empty());
+        order_id
+    }
+
+    /// Asks 0.5@100 of another user, 1@101 of alice and 1@102 of another user.
+    fn book_with_own_ask() -> (PairBook, uuid::Uuid) {
+        let mut book = PairBook::default();
+        rest(&mut book, BOB, OrderType::Sell, "100", "0.5");
+        let own = rest(&mut book, ALICE, OrderType::Sell, "101", "1");
+        rest(&mut book, BOB, OrderType::Sell, "102", "1");
+        (book, own)
+    }
+
+    fn fills(events: &[MatcherEvent]) -> Vec<(Decimal, Decimal)> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                MatcherEvent::Matched(matched) => Some((matched.rate, matched.amount)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn cancelled(events: &[MatcherEvent], order_id: uuid::Uuid) -> Option<(Decimal, CancelReason)> {
+        events.iter().find_map(|event| match event {
+            MatcherEvent::Cancelled(cancelled) if cancelled.order_id == order_id => {
+                Some((cancelled.remaining_amount, cancelled.reason.clone()))
+            }
+            _ => None,
+        })
+    }
+
+    fn decremented(events: &[MatcherEvent], order_id: uuid::Uuid) -> Option<(Decimal, Decimal)> {
+        events.iter().find_map(|event| match event {
+            MatcherEvent::Decremented(decremented) if decremented.order_id == order_id => {
+                Some((decremented.amount, decremented.remaining_amount))
+            }
+            _ => None,
+        })
+    }
+
+    fn resting_amount(book: &PairBook, order_id: uuid::Uuid) -> Option<Decimal> {
+        book.bids
+            .values()
+            .chain(book.asks.values())
+            .flatten()
+            .find(|entry| entry.order_id == order_id)
+            .map(|entry| entry.amount)
+    }
+
+    #[test]
+    fn fill_or_kill_reaching_own_order_is_rejected_without_fills() {
+        for mode in [
+            SelfTradePrevention::CancelNewest,
+            SelfTradePrevention::CancelBoth,
+            SelfTradePrevention::DecrementAndCancel,
+        ] {
+            let (mut book, own) = book_with_own_ask();
+            let taker = order(ALICE, OrderType::Buy, Some("102"), "1.5", TimeInForce::FillOrKill);
+            let taker_id = taker.order_id;
+
+            let events = book.match_order(taker, &pair(), mode, 0);
+
+            assert_eq!(events.len(), 1, "{:?}", mode);
+            assert_eq!(cancelled(&events, taker_id), Some((d("1.5"), CancelReason::SelfTrade)));
+            assert_eq!(resting_amount(&book, own), Some(d("1")));
+        }
+    }
+
+    #[test]
+    fn fill_or_kill_with_cancel_oldest_fills_past_own_order() {
+        let (mut book, own) = book_with_own_ask();
+        let taker = order(ALICE, OrderType::Buy, Some("102"), "1.5", TimeInForce::FillOrKill);
+        let taker_id = taker.order_id;
+
+        let events = book.match_order(taker, &pair(), SelfTradePrevention::CancelOldest, 0);
+
+        assert_eq!(fills(&events), vec![(d("100"), d("0.5")), (d("102"), d("1"))]);
+        assert_eq!(cancelled(&events, own), Some((d("1"), CancelReason::SelfTrade)));
+        assert_eq!(cancelled(&events, taker_id), None);
+        assert_eq!(resting_amount(&book, own), None);
+    }
+
+    #[test]
+    fn fill_or_kill_filled_before_own_order_is_accepted() {
+        let (mut book, own) = book_with_own_ask();
+        let taker = order(ALICE, OrderType::Buy, Some("102"), "0.5", TimeInForce::FillOrKill);
+
+        let events = book.match_order(taker, &pair(), SelfTradePrevention::CancelNewest, 0);
+
+        assert_eq!(fills(&events), vec![(d("100"), d("0.5"))]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(resting_amount(&book, own), Some(d("1")));
+    }
+
+    #[test]
+    fn immediate_or_cancel_with_cancel_newest_stops_at_own_order() {
+        let (mut book, own) = book_with_own_ask();
+        let taker = order(ALICE, OrderType::Buy, Some("102"), "1.5", TimeInForce::ImmediateOrCancel);
+        let taker_id = taker.order_id;
+
+        let events = book.match_order(taker, &pair(), SelfTradePrevention::CancelNewest, 0);
+
+        assert_eq!(fills(&events), vec![(d("100"), d("0.5"))]);
+        assert_eq!(cancelled(&events, taker_id), Some((d("1"), CancelReason::SelfTrade)));
+        assert_eq!(resting_amount(&book, own), Some(d("1")));
+    }
+
+    #[test]
+    fn immediate_or_cancel_with_cancel_oldest_keeps_matching() {
+        let (mut book, own) = book_with_own_ask();
+        let taker = order(ALICE, OrderType::Buy, Some("102"), "1.5", TimeInForce::ImmediateOrCancel);
+        let taker_id = taker.order_id;
+
+        let events = book.match_order(taker, &pair(), SelfTradePrevention::CancelOldest, 0);
+
+        assert_eq!(fills(&events), vec![(d("100"), d("0.5")), (d("102"), d("1"))]);
+        assert_eq!(cancelled(&events, own), Some((d("1"), CancelReason::SelfTrade)));
+        assert_eq!(cancelled(&events, taker_id), None);
+    }
+
+    #[test]
+    fn immediate_or_cancel_with_cancel_both_cancels_both_orders() {
+        let (mut book, own) = book_with_own_ask();
+        let taker = order(ALICE, OrderType::Buy, Some("102"), "1.5", TimeInForce::ImmediateOrCancel);
+        let taker_id = taker.order_id;
+
+        let events = book.match_order(taker, &pair(), SelfTradePrevention::CancelBoth, 0);
+
+        assert_eq!(fills(&events), vec![(d("100"), d("0.5"))]);
+        assert_eq!(cancelled(&events, own), Some((d("1"), CancelReason::SelfTrade)));
+        assert_eq!(cancelled(&events, taker_id), Some((d("1"), CancelReason::SelfTrade)));
+        assert_eq!(resting_amount(&book, own), None);
+    }
+
+    #[test]
+    fn immediate_or_cancel_with_decrement_and_cancel_removes_the_overlap() {
+        let (mut book, own) = book_with_own_ask();
+        let taker = order(ALICE, OrderType::Buy, Some("102"), "2", TimeInForce::ImmediateOrCancel);
+        let taker_id = taker.order_id;
+
+        let events = book.match_order(taker, &pair(), SelfTradePrevention::DecrementAndCancel, 0);
+
+        assert_eq!(fills(&events), vec![(d("100"), d("0.5")), (d("102"), d("0.5"))]);
+        assert_eq!(cancelled(&events, own), Some((d("1"), CancelReason::SelfTrade)));
+        assert_eq!(decremented(&events, taker_id), Some((d("1"), d("0.5"))));
+        assert_eq!(cancelled(&events, taker_id), None);
+    }
+
+    #[test]
+    fn decrement_and_cancel_shrinks_the_larger_resting_order() {
+        let mut book = PairBook::default();
+        let own = rest(&mut book, ALICE, OrderType::Buy, "100", "2");
+        let taker = order(ALICE, OrderType::Sell, Some("100"), "0.5", TimeInForce::ImmediateOrCancel);
+        let taker_id = taker.order_id;
+
+        let events = book.match_order(taker, &pair(), SelfTradePrevention::DecrementAndCancel, 0);
+
+        assert!(fills(&events).is_empty());
+        assert_eq!(decremented(&events, own), Some((d("0.5"), d("1.5"))));
+        assert_eq!(cancelled(&events, taker_id), Some((d("0.5"), CancelReason::SelfTrade)));
+        assert_eq!(resting_amount(&book, own), Some(d("1.5")));
+    }
+
+    #[test]
+    fn market_buy_with_cancel_newest_is_cancelled_at_own_order() {
+        let mut book = PairBook::default();
+        let own = rest(&mut book, ALICE, OrderType::Sell, "100", "0.5");
+        rest(&mut book, BOB, OrderType::Sell, "101", "1");
+        let taker = order(ALICE, OrderType::MarketBuy, None, "101", TimeInForce::ImmediateOrCancel);
+        let taker_id = taker.order_id;
+
+        let events = book.match_order(taker, &pair(), SelfTradePrevention::CancelNewest, 0);
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(cancelled(&events, taker_id), Some((d("101"), CancelReason::SelfTrade)));
+        assert_eq!(resting_amount(&book, own), Some(d("0.5")));
+    }
+
+    #[test]
+    fn market_buy_with_cancel_both_cancels_both_orders() {
+        let mut book = PairBook::default();
+        let own = rest(&mut book, ALICE, OrderType::Sell, "100", "0.5");
+        let taker = order(ALICE, OrderType::MarketBuy, None, "101", TimeInForce::ImmediateOrCancel);
+        let taker_id = taker.order_id;
+
+        let events = book.match_order(taker, &pair(), SelfTradePrevention::CancelBoth, 0);
+
+        assert!(fills(&events).is_empty());
+        assert_eq!(cancelled(&events, own), Some((d("0.5"), CancelReason::SelfTrade)));
+        assert_eq!(cancelled(&events, taker_id), Some((d("101"), CancelReason::SelfTrade)));
+    }
+
+    #[test]
+    fn market_buy_with_cancel_oldest_buys_past_own_order() {
+        let mut book = PairBook::default();
+        let own = rest(&mut book, ALICE, OrderType::Sell, "100", "0.5");
+        rest(&mut book, BOB, OrderType::Sell, "101", "1");
+        let taker = order(ALICE, OrderType::MarketBuy, None, "50.5", TimeInForce::ImmediateOrCancel);
+        let taker_id = taker.order_id;
+
+        let events = book.match_order(taker, &pair(), SelfTradePrevention::CancelOldest, 0);
+
+        assert_eq!(fills(&events), vec![(d("101"), d("0.5"))]);
+        assert_eq!(cancelled(&events, own), Some((d("0.5"), CancelReason::SelfTrade)));
+        assert_eq!(cancelled(&events, taker_id), None);
+    }
+
+    #[test]
+    fn market_buy_with_decrement_and_cancel_is_decremented_in_quote_currency() {
+        let mut book = PairBook::default();
+        let own = rest(&mut book, ALICE, OrderType::Sell, "100", "0.5");
+        rest(&mut book, BOB, OrderType::Sell, "101", "1");
+        let taker = order(ALICE, OrderType::MarketBuy, None, "101", TimeInForce::ImmediateOrCancel);
+        let taker_id = taker.order_id;
+
+        let events = book.match_order(taker, &pair(), SelfTradePrevention::DecrementAndCancel, 0);
+
+        // 0.5 BTC at 100 is taken off both orders, the remaining 51 JPY buy 0.504 BTC at 101
+        assert_eq!(cancelled(&events, own), Some((d("0.5"), CancelReason::SelfTrade)));
+        assert_eq!(decremented(&events, taker_id), Some((d("50"), d("51"))));
+        assert_eq!(fills(&events), vec![(d("101"), d("0.504"))]);
+        assert_eq!(cancelled(&events, taker_id), Some((d("0.096"), CancelReason::Unfilled)));
+    }
+
+    #[test]
+    fn market_buy_with_only_dust_left_is_cancelled_at_own_order() {
+        let mut book = PairBook::default();
+        let own = rest(&mut book, ALICE, OrderType::Sell, "100", "0.5");
+        let taker = order(ALICE, OrderType::MarketBuy, None, "0.05", TimeInForce::ImmediateOrCancel);
+        let taker_id = taker.order_id;
+
+        let events = book.match_order(taker, &pair(), SelfTradePrevention::DecrementAndCancel, 0);
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(cancelled(&events, taker_id), Some((d("0.05"), CancelReason::SelfTrade)));
+        assert_eq!(resting_amount(&book, own), Some(d("0.5")));
+    }
+
+    #[test]
+    fn fill_or_kill_market_buy_reaching_own_order_is_rejected() {
+        let mut book = PairBook::default();
+        rest(&mut book, BOB, OrderType::Sell, "100", "0.5");
+        rest(&mut book, ALICE, OrderType::Sell, "101", "1");
+        let taker = order(ALICE, OrderType::MarketBuy, None, "100", TimeInForce::FillOrKill);
+        let taker_id = taker.order_id;
+
+        let events = book.match_order(taker, &pair(), SelfTradePrevention::DecrementAndCancel, 0);
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(cancelled(&events, taker_id), Some((d("100"), CancelReason::SelfTrade)));
+    }
+
+    #[test]
+    fn replayed_order_continues_trade_ids_after_stored_fills() {
+        let (mut book, _) = book_with_own_ask();
+        let taker = order(ALICE, OrderType::Buy, Some("102"), "1.5", TimeInForce::GoodTilCancelled);
+        let taker_id = taker.order_id;
+        let events = book.match_order(taker, &pair(), SelfTradePrevention::CancelOldest, 2);
+
+        // The cancelled own ask carries no trade id, so the fills use consecutive indexes
+        let trade_ids: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                MatcherEvent::Matched(matched) => Some(matched.trade_id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(trade_ids, vec![trade_id(taker_id, 2), trade_id(taker_id, 3)]);
+    }
+
+    #[test]
+    fn market_buy_spends_quote_amount_in_whole_lots() {
+        let mut book = PairBook::default();
+        rest(&mut book, BOB, OrderType::Sell, "100", "0.5");
+        let ask = rest(&mut book, BOB, OrderType::Sell, "102", "1");
+        let taker = order(ALICE, OrderType::MarketBuy, None, "100", TimeInForce::GoodTilCancelled);
+        let taker_id = taker.order_id;
+        let events = book.match_order(taker, &pair(), SelfTradePrevention::CancelNewest, 0);
+
+        // 50 buys 0.5@100, the other 50 affords 0.490@102 and leaves 0.02 that buys no lot
+        assert_eq!(fills(&events), vec![(d("100"), d("0.5")), (d("102"), d("0.490"))]);
+        assert_eq!(cancelled(&events, taker_id), Some((d("0.02"), CancelReason::Unfilled)));
+        assert_eq!(resting_amount(&book, ask), Some(d("0.510")));
+    }
+
+    #[test]
+    fn market_sell_cancels_what_the_book_cannot_fill() {
+        let mut book = PairBook::default();
+        rest(&mut book, BOB, OrderType::Buy, "100", "1");
+        let taker = order(ALICE, OrderType::MarketSell, None, "1.5", TimeInForce::GoodTilCancelled);
+        let taker_id = taker.order_id;
+        let events = book.match_order(taker, &pair(), SelfTradePrevention::CancelNewest, 0);
+
+        assert_eq!(fills(&events), vec![(d("100"), d("1"))]);
+        assert_eq!(cancelled(&events, taker_id), Some((d("0.5"), CancelReason::Unfilled)));
+        assert!(book.bids.is_empty());
+    }
+
+    #[test]
+    fn immediate_or_cancel_expires_its_remainder() {
+        let mut book = PairBook::default();
+        rest(&mut book, BOB, OrderType::Sell, "100", "0.5");
+        let taker = order(ALICE, OrderType::Buy, Some("101"), "1", TimeInForce::ImmediateOrCancel);
+        let taker_id = taker.order_id;
+        let events = book.match_order(taker, &pair(), SelfTradePrevention::CancelNewest, 0);
+
+        assert_eq!(fills(&events), vec![(d("100"), d("0.5"))]);
+        assert_eq!(cancelled(&events, taker_id), Some((d("0.5"), CancelReason::Expired)));
+        assert_eq!(resting_amount(&book, taker_id), None);
+    }
+
+    #[test]
+    fn fill_or_kill_fills_completely_or_not_at_all() {
+        let mut book = PairBook::default();
+        let ask = rest(&mut book, BOB, OrderType::Sell, "100", "0.5");
+        rest(&mut book, BOB, OrderType::Sell, "102", "1");
+
+        let killed = order(ALICE, OrderType::Buy, Some("101"), "1", TimeInForce::FillOrKill);
+        let killed_id = killed.order_id;
+        let events = book.match_order(killed, &pair(), SelfTradePrevention::CancelNewest, 0);
+        assert_eq!(events.len(), 1);
+        assert_eq!(cancelled(&events, killed_id), Some((d("1"), CancelReason::FillOrKill)));
+        assert_eq!(resting_amount(&book, ask), Some(d("0.5")));
+
+        let filled = order(ALICE, OrderType::Buy, Some("102"), "1", TimeInForce::FillOrKill);
+        let events = book.match_order(filled, &pair(), SelfTradePrevention::CancelNewest, 0);
+        assert_eq!(fills(&events), vec![(d("100"), d("0.5")), (d("102"), d("0.5"))]);
+        assert_eq!(events.len(), 2);
+    }
+
+    #[test]
+    fn post_only_is_rejected_when_it_would_cross() {
+        let mut book = PairBook::default();
+        let ask = rest(&mut book, BOB, OrderType::Sell, "100", "0.5");
+
+        let crossing = order(ALICE, OrderType::Buy, Some("100"), "1", TimeInForce::PostOnly);
+        let crossing_id = crossing.order_id;
+        let events = book.match_order(crossing, &pair(), SelfTradePrevention::CancelNewest, 0);
+        assert_eq!(events.len(), 1);
+        assert_eq!(cancelled(&events, crossing_id), Some((d("1"), CancelReason::PostOnly)));
+        assert_eq!(resting_amount(&book, ask), Some(d("0.5")));
+
+        let maker = order(ALICE, OrderType::Buy, Some("99"), "1", TimeInForce::PostOnly);
+        let maker_id = maker.order_id;
+        assert!(book.match_order(maker, &pair(), SelfTradePrevention::CancelNewest, 0).is_empty());
+        assert_eq!(resting_amount(&book, maker_id), Some(d("1")));
+    }
+}
@@ -0,0 +1,392 @@
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use crc::{Crc, CRC_32_ISO_HDLC};
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::matcher::{OrderMatcher, SelfTradePrevention};
+
+// File layout: MAGIC, version (u32), payload length (u64), payload CRC32 (u32), payload.
+// All integers are little endian.
+const MAGIC: &[u8; 8] = b"CEXSNAP\0";
+// Version 2 added the recently matched order ids, version 3 client order ids
+const VERSION: u32 = 3;
+const HEADER_LEN: usize = MAGIC.len() + 4 + 8 + 4;
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+// Older snapshots kept next to the newest one, in case it turns out to be unreadable
+const KEEP_SNAPSHOTS: usize = 3;
+
+/// Order books together with the next `orders` offset to consume on each partition.
+pub struct Snapshot {
+    pub offsets: HashMap<i32, i64>,
+    pub matcher: OrderMatcher,
+}
+
+/// Writes a snapshot to `dir` and removes all but the newest few.
+/// The file is written under a temporary name and renamed, so readers never see a partial file.
+pub fn save(dir: &Path, matcher: &OrderMatcher, offsets: &HashMap<i32, i64>) -> Result<PathBuf> {
+    let mut payload = Vec::new();
+    let mut partitions: Vec<_> = offsets.iter().collect();
+    partitions.sort();
+    put_u32(&mut payload, partitions.len() as u32);
+    for (&partition, &offset) in partitions {
+        put_i32(&mut payload, partition);
+        put_i64(&mut payload, offset);
+    }
+    matcher.encode(&mut payload);
+
+    let mut file = Vec::with_capacity(HEADER_LEN + payload.len());
+    file.extend_from_slice(MAGIC);
+    put_u32(&mut file, VERSION);
+    put_u64(&mut file, payload.len() as u64);
+    put_u32(&mut file, CRC32.checksum(&payload));
+    file.extend_from_slice(&payload);
+
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!("snapshot-{:020}.bin", Utc::now().timestamp_micros()));
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, &file)?;
+    std::fs::rename(&tmp_path, &path)?;
+
+    for old in snapshot_files(dir)?.into_iter().skip(KEEP_SNAPSHOTS) {
+        std::fs::remove_file(old)?;
+    }
+
+    Ok(path)
+}
+
+/// Loads the newest snapshot in `dir` that passes the version and checksum checks.
+/// Returns `None` if there is none, in which case the books are rebuilt from the database.
+pub fn load_latest(
+    dir: &Path,
+    pairs: &shared::PairRegistry,
+    self_trade_prevention: SelfTradePrevention,
+) -> Result<Option<Snapshot>> {
+    if !dir.exists() {
+        return Ok(None);
+    }
+
+    for path in snapshot_files(dir)? {
+        match std::fs::read(&path).map_err(Into::into).and_then(|bytes| decode(&bytes, pairs, self_trade_prevention)) {
+            Ok(snapshot) => {
+                println!("Loaded snapshot {}", path.display());
+                return Ok(Some(snapshot));
+            }
+            Err(e) => eprintln!("Skipping unreadable snapshot {}: {}", path.display(), e),
+        }
+    }
+
+    Ok(None)
+}
+
+/// Snapshot files in `dir`, newest first.
+fn snapshot_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_snapshot = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("snapshot-") && name.ends_with(".bin"));
+        if is_snapshot {
+            files.push(path);
+        }
+    }
+    // The zero-padded timestamp makes name order chronological
+    files.sort_by(|a, b| b.cmp(a));
+
+    Ok(files)
+}
+
+fn decode(
+    bytes: &[u8],
+    pairs: &shared::PairRegistry,
+    self_trade_prevention: SelfTradePrevention,
+) -> Result<Snapshot> {
+    let mut reader = Reader::new(bytes);
+    if reader.take(MAGIC.len())? != MAGIC {
+        bail!("not a snapshot file");
+    }
+    let version = reader.u32()?;
+    if version != VERSION {
+        bail!("unsupported snapshot version {}", version);
+    }
+    let len = reader.u64()? as usize;
+    let checksum = reader.u32()?;
+    let payload = reader.take(len)?;
+    if CRC32.checksum(payload) != checksum {
+        bail!("checksum mismatch");
+    }
+
+    let mut reader = Reader::new(payload);
+    let mut offsets = HashMap::new();
+    for _ in 0..reader.u32()? {
+        let partition = reader.i32()?;
+        offsets.insert(partition, reader.i64()?);
+    }
+    let matcher = OrderMatcher::decode(pairs.clone(), self_trade_prevention, &mut reader)?;
+    if !reader.is_empty() {
+        bail!("trailing bytes after order books");
+    }
+
+    Ok(Snapshot { offsets, matcher })
+}
+
+pub fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_i32(buf: &mut Vec<u8>, value: i32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_i64(buf: &mut Vec<u8>, value: i64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_str(buf: &mut Vec<u8>, value: &str) {
+    put_u32(buf, value.len() as u32);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+pub fn put_opt_str(buf: &mut Vec<u8>, value: Option<&str>) {
+    match value {
+        Some(value) => {
+            buf.push(1);
+            put_str(buf, value);
+        }
+        None => buf.push(0),
+    }
+}
+
+pub fn put_decimal(buf: &mut Vec<u8>, value: Decimal) {
+    buf.extend_from_slice(&value.serialize());
+}
+
+pub fn put_uuid(buf: &mut Vec<u8>, value: uuid::Uuid) {
+    buf.extend_from_slice(value.as_bytes());
+}
+
+pub fn put_datetime(buf: &mut Vec<u8>, value: DateTime<Utc>) {
+    put_i64(buf, value.timestamp_micros());
+}
+
+/// Reads the primitives written by the `put_*` functions, failing on truncated input.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < len {
+            bail!("snapshot is truncated");
+        }
+        let (head, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().expect("take returns exactly N bytes"))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn i64(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.array()?))
+    }
+
+    pub fn str(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        Ok(String::from_utf8(self.take(len)?.to_vec())?)
+    }
+
+    pub fn opt_str(&mut self) -> Result<Option<String>> {
+        match self.take(1)?[0] {
+            0 => Ok(None),
+            1 => Ok(Some(self.str()?)),
+            flag => bail!("invalid option flag {}", flag),
+        }
+    }
+
+    pub fn decimal(&mut self) -> Result<Decimal> {
+        Ok(Decimal::deserialize(self.array()?))
+    }
+
+    pub fn uuid(&mut self) -> Result<uuid::Uuid> {
+        Ok(uuid::Uuid::from_bytes(self.array()?))
+    }
+
+    pub fn datetime(&mut self) -> Result<DateTime<Utc>> {
+        let micros = self.i64()?;
+        DateTime::from_timestamp_micros(micros).ok_or_else(|| anyhow!("invalid timestamp {}", micros))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::{OrderMessage, OrderType, PairModel, PairRegistry, TimeInForce};
+
+    fn pairs() -> PairRegistry {
+        PairRegistry::from_iter([PairModel {
+            pair: "btc_jpy".to_string(),
+            base_currency: "BTC".to_string(),
+            quote_currency: "JPY".to_string(),
+            tick_size: "1".parse().unwrap(),
+            lot_size: "0.001".parse().unwrap(),
+            min_notional: Decimal::ZERO,
+            min_amount: Decimal::ZERO,
+            status: "active".to_string(),
+            created_at: Utc::now(),
+        }])
+    }
+
+    fn order(order_type: OrderType, rate: &str, amount: &str) -> OrderMessage {
+        OrderMessage {
+            order_id: uuid::Uuid::new_v4(),
+            user_id: "alice".to_string(),
+            pair: "btc_jpy".to_string(),
+            order_type,
+            rate: Some(rate.parse().unwrap()),
+            amount: amount.parse().unwrap(),
+            time_in_force: TimeInForce::GoodTilCancelled,
+            client_order_id: Some("client".to_string()),
+            created_at: Utc::now(),
+        }
+    }
+
+    /// A fresh directory under the system temp dir, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("macher-snapshot-{}", uuid::Uuid::new_v4())))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn encoded(matcher: &OrderMatcher) -> Vec<u8> {
+        let mut buf = Vec::new();
+        matcher.encode(&mut buf);
+        buf
+    }
+
+    /// The bytes of a snapshot holding one bid and one ask.
+    async fn snapshot_bytes(dir: &Path) -> Vec<u8> {
+        let mut matcher = OrderMatcher::new(pairs(), SelfTradePrevention::CancelNewest);
+        matcher.match_order(order(OrderType::Buy, "99", "1")).await.unwrap();
+        matcher.match_order(order(OrderType::Sell, "101", "0.5")).await.unwrap();
+        let path = save(dir, &matcher, &HashMap::from([(0, 7)])).unwrap();
+        std::fs::read(path).unwrap()
+    }
+
+    fn decode_error(bytes: &[u8]) -> String {
+        match decode(bytes, &pairs(), SelfTradePrevention::CancelNewest) {
+            Ok(_) => panic!("corrupt snapshot was decoded"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trips_books_offsets_and_matched_orders() {
+        let dir = TempDir::new();
+        let mut matcher = OrderMatcher::new(pairs(), SelfTradePrevention::CancelNewest);
+        let bid = order(OrderType::Buy, "99", "1");
+        matcher.match_order(bid.clone()).await.unwrap();
+        matcher.match_order(order(OrderType::Sell, "101", "0.5")).await.unwrap();
+        let offsets = HashMap::from([(0, 7), (1, 3)]);
+        save(&dir.0, &matcher, &offsets).unwrap();
+
+        let mut snapshot = load_latest(&dir.0, &pairs(), SelfTradePrevention::CancelNewest)
+            .unwrap()
+            .expect("snapshot was saved");
+        assert_eq!(snapshot.offsets, offsets);
+        assert_eq!(encoded(&snapshot.matcher), encoded(&matcher));
+        // The restored matcher still recognises orders it matched before the snapshot
+        assert!(snapshot.matcher.match_order(bid).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_a_checksum_mismatch() {
+        let dir = TempDir::new();
+        let mut bytes = snapshot_bytes(&dir.0).await;
+        *bytes.last_mut().unwrap() ^= 0xff;
+        assert_eq!(decode_error(&bytes), "checksum mismatch");
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_input() {
+        let dir = TempDir::new();
+        let bytes = snapshot_bytes(&dir.0).await;
+        assert_eq!(decode_error(&bytes[..bytes.len() - 1]), "snapshot is truncated");
+        assert_eq!(decode_error(&bytes[..HEADER_LEN - 1]), "snapshot is truncated");
+    }
+
+    #[tokio::test]
+    async fn rejects_an_unknown_version() {
+        let dir = TempDir::new();
+        let mut bytes = snapshot_bytes(&dir.0).await;
+        bytes[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(decode_error(&bytes), format!("unsupported snapshot version {}", VERSION + 1));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_an_older_snapshot() {
+        let dir = TempDir::new();
+        let matcher = OrderMatcher::new(pairs(), SelfTradePrevention::CancelNewest);
+        save(&dir.0, &matcher, &HashMap::from([(0, 1)])).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let newest = save(&dir.0, &matcher, &HashMap::from([(0, 2)])).unwrap();
+        std::fs::write(newest, b"CEXSNAP\0").unwrap();
+
+        let snapshot = load_latest(&dir.0, &pairs(), SelfTradePrevention::CancelNewest)
+            .unwrap()
+            .expect("the older snapshot is readable");
+        assert_eq!(snapshot.offsets, HashMap::from([(0, 1)]));
+    }
+
+    #[test]
+    fn keeps_only_the_newest_snapshots() {
+        let dir = TempDir::new();
+        let matcher = OrderMatcher::new(pairs(), SelfTradePrevention::CancelNewest);
+        let mut saved = Vec::new();
+        for offset in 0..5 {
+            saved.push(save(&dir.0, &matcher, &HashMap::from([(0, offset)])).unwrap());
+            // Snapshot names carry a microsecond timestamp
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+
+        saved.reverse();
+        assert_eq!(snapshot_files(&dir.0).unwrap(), saved[..KEEP_SNAPSHOTS].to_vec());
+    }
+}
@@ -0,0 +1,7 @@
+-- Track the funds each order currently holds in balances.locked
+ALTER TABLE orders ADD COLUMN IF NOT EXISTS locked_amount DECIMAL(30, 8) NOT NULL DEFAULT 0;
+
+-- Backfill open orders with what create_order locked for their remainder
+UPDATE orders
+SET locked_amount = CASE WHEN order_type = 'buy' THEN remaining_amount * rate ELSE remaining_amount END
+WHERE status IN ('pending', 'partially_filled');
@@ -0,0 +1,6 @@
+-- Market orders have no limit rate; market_buy amounts are in the quote currency
+ALTER TABLE orders ALTER COLUMN order_type TYPE VARCHAR(20);
+ALTER TABLE orders DROP CONSTRAINT IF EXISTS orders_order_type_check;
+ALTER TABLE orders ADD CONSTRAINT orders_order_type_check
+    CHECK (order_type IN ('buy', 'sell', 'market_buy', 'market_sell'));
+ALTER TABLE orders ALTER COLUMN rate DROP NOT NULL;
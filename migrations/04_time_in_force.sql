@@ -0,0 +1,3 @@
+-- Time in force of each order; existing orders were good-til-cancelled
+ALTER TABLE orders ADD COLUMN IF NOT EXISTS time_in_force VARCHAR(20) NOT NULL DEFAULT 'good_til_cancelled'
+    CHECK (time_in_force IN ('good_til_cancelled', 'immediate_or_cancel', 'fill_or_kill', 'post_only'));
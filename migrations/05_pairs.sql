@@ -0,0 +1,22 @@
+-- Trading pair registry shared by the API server, matcher and settlement
+CREATE TABLE IF NOT EXISTS pairs (
+    pair VARCHAR(20) PRIMARY KEY,
+    base_currency VARCHAR(10) NOT NULL,
+    quote_currency VARCHAR(10) NOT NULL,
+    tick_size DECIMAL(30, 8) NOT NULL,
+    lot_size DECIMAL(30, 8) NOT NULL,
+    min_notional DECIMAL(30, 8) NOT NULL DEFAULT 0,
+    status VARCHAR(20) NOT NULL DEFAULT 'active' CHECK (status IN ('active', 'suspended')),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+INSERT INTO pairs (pair, base_currency, quote_currency, tick_size, lot_size, min_notional) VALUES
+    ('btc_jpy', 'BTC', 'JPY', 1, 0.00000001, 500),
+    ('eth_jpy', 'ETH', 'JPY', 1, 0.00000001, 500)
+ON CONFLICT (pair) DO NOTHING;
+
+ALTER TABLE orders ADD CONSTRAINT orders_pair_fkey FOREIGN KEY (pair) REFERENCES pairs (pair);
+
+INSERT INTO balances (user_id, currency, balance) VALUES
+    ('default_user', 'ETH', 10.0)
+ON CONFLICT (user_id, currency) DO NOTHING;
@@ -0,0 +1,14 @@
+-- API keys for Coincheck-style private API authentication
+CREATE TABLE IF NOT EXISTS api_keys (
+    access_key VARCHAR(64) PRIMARY KEY,
+    secret_key VARCHAR(128) NOT NULL,
+    user_id VARCHAR(255) NOT NULL,
+    can_trade BOOLEAN NOT NULL DEFAULT FALSE,
+    can_read BOOLEAN NOT NULL DEFAULT TRUE,
+    can_withdraw BOOLEAN NOT NULL DEFAULT FALSE,
+    last_nonce BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_api_keys_user_id ON api_keys(user_id);
+
@@ -0,0 +1,26 @@
+-- User accounts owning balances, orders and API keys
+CREATE TABLE IF NOT EXISTS users (
+    id VARCHAR(255) PRIMARY KEY,
+    email VARCHAR(255) NOT NULL UNIQUE,
+    status VARCHAR(20) NOT NULL DEFAULT 'active' CHECK (status IN ('active', 'frozen', 'closed')),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+INSERT INTO users (id, email) VALUES
+    ('default_user', 'default_user@example.com')
+ON CONFLICT (id) DO NOTHING;
+
+-- Create accounts for user ids that were used before the users table existed
+INSERT INTO users (id, email)
+SELECT user_id, user_id || '@example.invalid'
+FROM (
+    SELECT user_id FROM balances
+    UNION SELECT user_id FROM orders
+    UNION SELECT user_id FROM api_keys
+) existing
+ON CONFLICT (id) DO NOTHING;
+
+ALTER TABLE balances ADD CONSTRAINT balances_user_id_fkey FOREIGN KEY (user_id) REFERENCES users (id);
+ALTER TABLE orders ADD CONSTRAINT orders_user_id_fkey FOREIGN KEY (user_id) REFERENCES users (id);
+ALTER TABLE api_keys ADD CONSTRAINT api_keys_user_id_fkey FOREIGN KEY (user_id) REFERENCES users (id);
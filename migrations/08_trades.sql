@@ -0,0 +1,19 @@
+-- Individual fills, separate from the orders they belong to
+CREATE TABLE IF NOT EXISTS trades (
+    id UUID PRIMARY KEY,
+    pair VARCHAR(20) NOT NULL REFERENCES pairs (pair),
+    rate DECIMAL(30, 8) NOT NULL,
+    amount DECIMAL(30, 8) NOT NULL,
+    taker_side VARCHAR(4) NOT NULL CHECK (taker_side IN ('buy', 'sell')),
+    maker_order_id UUID NOT NULL REFERENCES orders (id),
+    taker_order_id UUID NOT NULL REFERENCES orders (id),
+    maker_user_id VARCHAR(255) NOT NULL REFERENCES users (id),
+    taker_user_id VARCHAR(255) NOT NULL REFERENCES users (id),
+    maker_fee DECIMAL(30, 8) NOT NULL DEFAULT 0,
+    taker_fee DECIMAL(30, 8) NOT NULL DEFAULT 0,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_trades_pair_created_at ON trades(pair, created_at);
+CREATE INDEX idx_trades_maker_user_id ON trades(maker_user_id, created_at);
+CREATE INDEX idx_trades_taker_user_id ON trades(taker_user_id, created_at);
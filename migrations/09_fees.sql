@@ -0,0 +1,24 @@
+-- Maker/taker fee rates per pair and volume tier
+CREATE TABLE IF NOT EXISTS fee_schedules (
+    pair VARCHAR(20) NOT NULL REFERENCES pairs (pair),
+    tier INTEGER NOT NULL,
+    -- Minimum 30-day traded volume in the pair's quote currency to reach the tier
+    min_volume_30d DECIMAL(30, 8) NOT NULL,
+    maker_fee_rate DECIMAL(10, 6) NOT NULL,
+    taker_fee_rate DECIMAL(10, 6) NOT NULL,
+    PRIMARY KEY (pair, tier)
+);
+
+INSERT INTO fee_schedules (pair, tier, min_volume_30d, maker_fee_rate, taker_fee_rate) VALUES
+    ('btc_jpy', 0, 0, 0.001, 0.002),
+    ('btc_jpy', 1, 10000000, 0.0005, 0.0015),
+    ('btc_jpy', 2, 100000000, 0, 0.001),
+    ('eth_jpy', 0, 0, 0.001, 0.002),
+    ('eth_jpy', 1, 10000000, 0.0005, 0.0015),
+    ('eth_jpy', 2, 100000000, 0, 0.001)
+ON CONFLICT (pair, tier) DO NOTHING;
+
+-- House account collecting trading fees
+INSERT INTO users (id, email) VALUES
+    ('fee_account', 'fee_account@example.com')
+ON CONFLICT (id) DO NOTHING;
@@ -0,0 +1,3 @@
+-- Caller-chosen order id, lets clients retry order placement without creating duplicates
+ALTER TABLE orders ADD COLUMN IF NOT EXISTS client_order_id VARCHAR(64);
+ALTER TABLE orders ADD CONSTRAINT orders_user_id_client_order_id_key UNIQUE (user_id, client_order_id);
@@ -0,0 +1,26 @@
+-- OHLCV candles per pair and interval, built by the aggregator from the fills in `matched-orders`
+CREATE TABLE IF NOT EXISTS candles (
+    pair VARCHAR(20) NOT NULL REFERENCES pairs (pair),
+    -- `1m`, `5m`, `1h` or `1d`
+    interval VARCHAR(3) NOT NULL CHECK (interval IN ('1m', '5m', '1h', '1d')),
+    open_time TIMESTAMP WITH TIME ZONE NOT NULL,
+    open DECIMAL(30, 8) NOT NULL,
+    high DECIMAL(30, 8) NOT NULL,
+    low DECIMAL(30, 8) NOT NULL,
+    close DECIMAL(30, 8) NOT NULL,
+    -- Base currency amount traded
+    volume DECIMAL(30, 8) NOT NULL,
+    PRIMARY KEY (pair, interval, open_time)
+);
+
+-- Next `matched-orders` offset per partition, written in the same transaction as the candles
+CREATE TABLE IF NOT EXISTS candle_offsets (
+    partition INTEGER PRIMARY KEY,
+    next_offset BIGINT NOT NULL
+);
+
+-- Trade ids already counted in `candles`. The matcher republishes fills with their original
+-- trade id after a restart, and the aggregator counts each id once.
+CREATE TABLE IF NOT EXISTS candle_trades (
+    trade_id UUID PRIMARY KEY
+);
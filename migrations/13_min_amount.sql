@@ -0,0 +1,5 @@
+-- Smallest base currency amount an order may be placed for
+ALTER TABLE pairs ADD COLUMN IF NOT EXISTS min_amount DECIMAL(30, 8) NOT NULL DEFAULT 0;
+
+UPDATE pairs SET min_amount = 0.001 WHERE pair = 'btc_jpy';
+UPDATE pairs SET min_amount = 0.01 WHERE pair = 'eth_jpy';
@@ -0,0 +1,11 @@
+-- Parts of orders removed by the matcher's decrement-and-cancel self-trade prevention. The id
+-- is derived like a trade id, so settlement applies a redelivered decrement once.
+CREATE TABLE IF NOT EXISTS order_decrements (
+    id UUID PRIMARY KEY,
+    order_id UUID NOT NULL REFERENCES orders (id),
+    -- Base currency amount, or quote currency amount for `market_buy` orders
+    amount DECIMAL(30, 8) NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_order_decrements_order_id ON order_decrements(order_id);
@@ -0,0 +1,4 @@
+-- Development key for the test user. Never run this against a deployment: the secret is public.
+INSERT INTO api_keys (access_key, secret_key, user_id, can_trade, can_read) VALUES
+    ('default_access_key', 'default_secret_key', 'default_user', TRUE, TRUE)
+ON CONFLICT (access_key) DO NOTHING;
@@ -0,0 +1,274 @@
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use hmac::{Hmac, Mac};
+use sea_orm::{DatabaseConnection, Set};
+use shared::{ApiKeyActiveModel, ApiKeyModel, CexError};
+use uuid::Uuid;
+use sha2::Sha256;
+
+use crate::{db, AppState};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Private API request bodies are small JSON documents
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Trade,
+    Read,
+    // No withdrawal endpoints yet, the permission is stored for API compatibility
+    #[allow(dead_code)]
+    Withdraw,
+}
+
+/// The API key owner of an authenticated request, inserted into request extensions.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub user_id: String,
+    pub can_trade: bool,
+    pub can_read: bool,
+    pub can_withdraw: bool,
+}
+
+impl AuthenticatedUser {
+    pub fn require(&self, permission: Permission) -> Result<(), CexError> {
+        let allowed = match permission {
+            Permission::Trade => self.can_trade,
+            Permission::Read => self.can_read,
+            Permission::Withdraw => self.can_withdraw,
+        };
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(CexError::Forbidden(format!(
+                "API key does not have {:?} permission",
+                permission
+            )))
+        }
+    }
+}
+
+/// Generates a new random access key and secret for `user_id`.
+pub fn new_api_key(
+    user_id: &str,
+    can_trade: bool,
+    can_read: bool,
+    can_withdraw: bool,
+) -> ApiKeyActiveModel {
+    let access_key = Uuid::new_v4().simple().to_string();
+    let secret_key = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+
+    ApiKeyActiveModel {
+        access_key: Set(access_key),
+        secret_key: Set(secret_key),
+        user_id: Set(user_id.to_string()),
+        can_trade: Set(can_trade),
+        can_read: Set(can_read),
+        can_withdraw: Set(can_withdraw),
+        last_nonce: Set(0),
+        created_at: Set(chrono::Utc::now()),
+    }
+}
+
+/// Returns true if `signature` is the hex HMAC-SHA256 of `nonce + url + body` under `secret`.
+pub fn verify_signature(secret: &str, nonce: &str, url: &str, body: &[u8], signature: &str) -> bool {
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+    let Ok(mut mac) = HmacSha256::new_from_slice(secret.as_bytes()) else {
+        return false;
+    };
+
+    mac.update(nonce.as_bytes());
+    mac.update(url.as_bytes());
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Checks a request signed with `api_key` and returns its nonce. The nonce must be greater than
+/// the last one the key used, the caller still has to record it to reject concurrent replays.
+pub fn check_request(
+    api_key: &ApiKeyModel,
+    nonce: &str,
+    signature: &str,
+    url: &str,
+    body: &[u8],
+) -> Result<i64, CexError> {
+    let nonce_value: i64 = nonce
+        .parse()
+        .map_err(|_| unauthorized("ACCESS-NONCE must be an integer"))?;
+
+    if !verify_signature(&api_key.secret_key, nonce, url, body, signature) {
+        return Err(unauthorized("Invalid signature"));
+    }
+    // Checked after the signature so unsigned requests cannot probe nonces
+    if nonce_value <= api_key.last_nonce {
+        return Err(stale_nonce());
+    }
+
+    Ok(nonce_value)
+}
+
+/// Verifies a signed request for `access_key` and consumes its nonce.
+pub async fn authenticate_key(
+    db: &DatabaseConnection,
+    access_key: &str,
+    nonce: &str,
+    signature: &str,
+    url: &str,
+    body: &[u8],
+) -> Result<AuthenticatedUser, CexError> {
+    let api_key = db::find_api_key(db, access_key).await?
+        .ok_or_else(|| unauthorized("Invalid API key"))?;
+
+    let nonce_value = check_request(&api_key, nonce, signature, url, body)?;
+
+    // Another request may have used the nonce since the key was loaded
+    let accepted = db::advance_nonce(db, access_key, nonce_value).await?;
+    if !accepted {
+        return Err(stale_nonce());
+    }
+
+    Ok(AuthenticatedUser {
+        user_id: api_key.user_id,
+        can_trade: api_key.can_trade,
+        can_read: api_key.can_read,
+        can_withdraw: api_key.can_withdraw,
+    })
+}
+
+/// Middleware authenticating Coincheck-style private API requests.
+///
+/// Clients send ACCESS-KEY, ACCESS-NONCE and ACCESS-SIGNATURE headers, where the signature is the
+/// hex HMAC-SHA256 of `nonce + url + body` keyed with the API secret and the URL is the full
+/// request URL as seen by the client.
+pub async fn authenticate(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, CexError> {
+    let (mut parts, body) = request.into_parts();
+
+    let access_key = header(&parts.headers, "ACCESS-KEY")?;
+    let nonce = header(&parts.headers, "ACCESS-NONCE")?;
+    let signature = header(&parts.headers, "ACCESS-SIGNATURE")?;
+    let url = request_url(&parts.headers, &parts.uri);
+
+    let body = to_bytes(body, MAX_BODY_BYTES)
+        .await
+        .map_err(|_| CexError::PayloadTooLarge)?;
+
+    let user = authenticate_key(&state.db, &access_key, &nonce, &signature, &url, &body).await?;
+
+    parts.extensions.insert(user);
+    Ok(next.run(Request::from_parts(parts, Body::from(body))).await)
+}
+
+/// Reconstructs the URL the client signed from the Host header, honouring a proxy's X-Forwarded-Proto.
+pub fn request_url(headers: &HeaderMap, uri: &axum::http::Uri) -> String {
+    let scheme = headers
+        .get("X-Forwarded-Proto")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("http");
+    let host = headers
+        .get("Host")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+
+    format!("{}://{}{}", scheme, host, path)
+}
+
+fn header(headers: &HeaderMap, name: &str) -> Result<String, CexError> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+        .ok_or_else(|| unauthorized(&format!("Missing {} header", name)))
+}
+
+fn unauthorized(message: &str) -> CexError {
+    CexError::Unauthorized(message.to_string())
+}
+
+fn stale_nonce() -> CexError {
+    unauthorized("Nonce must be greater than the previous one")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "https://example.com/api/exchange/orders";
+    const BODY: &[u8] = br#"{"pair":"btc_jpy","order_type":"buy","rate":"100","amount":"1"}"#;
+
+    fn api_key(last_nonce: i64) -> ApiKeyModel {
+        ApiKeyModel {
+            access_key: "access".to_string(),
+            secret_key: "secret".to_string(),
+            user_id: "alice".to_string(),
+            can_trade: true,
+            can_read: true,
+            can_withdraw: false,
+            last_nonce,
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    fn sign(nonce: &str, url: &str, body: &[u8]) -> String {
+        let mut mac = HmacSha256::new_from_slice(b"secret").unwrap();
+        mac.update(nonce.as_bytes());
+        mac.update(url.as_bytes());
+        mac.update(body);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn is_unauthorized(result: Result<i64, CexError>, message: &str) -> bool {
+        matches!(result, Err(CexError::Unauthorized(m)) if m == message)
+    }
+
+    #[test]
+    fn accepts_a_valid_signature() {
+        let signature = sign("2", URL, BODY);
+        assert_eq!(check_request(&api_key(1), "2", &signature, URL, BODY).unwrap(), 2);
+    }
+
+    #[test]
+    fn rejects_a_tampered_body() {
+        let signature = sign("2", URL, BODY);
+        let tampered = br#"{"pair":"btc_jpy","order_type":"buy","rate":"100","amount":"9"}"#;
+        let result = check_request(&api_key(1), "2", &signature, URL, tampered);
+        assert!(is_unauthorized(result, "Invalid signature"));
+    }
+
+    #[test]
+    fn rejects_a_tampered_nonce() {
+        let signature = sign("2", URL, BODY);
+        let result = check_request(&api_key(1), "3", &signature, URL, BODY);
+        assert!(is_unauthorized(result, "Invalid signature"));
+    }
+
+    #[test]
+    fn rejects_a_stale_nonce() {
+        let signature = sign("2", URL, BODY);
+        let result = check_request(&api_key(5), "2", &signature, URL, BODY);
+        assert!(is_unauthorized(result, "Nonce must be greater than the previous one"));
+    }
+
+    #[test]
+    fn rejects_a_replayed_nonce() {
+        let signature = sign("2", URL, BODY);
+        let nonce = check_request(&api_key(1), "2", &signature, URL, BODY).unwrap();
+
+        // The key's last nonce after the first request was recorded
+        let result = check_request(&api_key(nonce), "2", &signature, URL, BODY);
+        assert!(is_unauthorized(result, "Nonce must be greater than the previous one"));
+    }
+}
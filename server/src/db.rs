@@ -1,95 +1,119 @@
-use sea_orm::{DatabaseConnection, EntityTrait, QueryFilter, ColumnTrait, TransactionTrait, ActiveModelTrait, Set, FromQueryResult, QueryOrder, QuerySelect};
-use shared::{Balance, BalanceActiveModel, BalanceColumn, OrderEntity, OrderActiveModel, OrderColumn};
-use shared::{Order, OrderStatus, OrderType};
+use sea_orm::{DatabaseConnection, DatabaseTransaction, DbErr, SqlErr, EntityTrait, QueryFilter, ColumnTrait, TransactionTrait, ActiveModelTrait, Set, QueryOrder, QuerySelect, ConnectionTrait, Condition};
+use shared::{ApiKey, ApiKeyActiveModel, ApiKeyModel, User, UserActiveModel, UserColumn, UserModel};
+use shared::{Balance, BalanceColumn, BalanceModel, OrderEntity, OrderActiveModel, OrderColumn, OrderModel};
+use shared::{Trade, TradeColumn, TradeModel};
+use shared::{CandleColumn, CandleEntity, CandleInterval, CandleModel};
+use shared::{OutboxActiveModel, OrderCommand};
+use shared::{AccountStatus, CexError, Order, OrderMessage, OrderStatus, OrderType};
 use rust_decimal::Decimal;
 use uuid::Uuid;
+use chrono::{DateTime, Utc};
 
-#[derive(FromQueryResult)]
-struct BalanceRow {
-    #[allow(dead_code)]
-    user_id: String,
-    #[allow(dead_code)]
-    currency: String,
-    balance: Decimal,
-    locked: Decimal,
+pub enum PlaceOrderOutcome {
+    Placed,
+    /// The user already has an order with this `client_order_id`
+    Duplicate(Box<OrderModel>),
 }
 
-pub async fn check_and_lock_balance(
+/// Locks the balance, records the order and queues its command for the outbox relay in one
+/// transaction, so an order is only published if its funds are locked and vice versa.
+/// Nothing is created if the `client_order_id` is taken or the available balance is
+/// insufficient, which is reported as `CexError::InsufficientBalance`.
+pub async fn place_order(
     db: &DatabaseConnection,
-    user_id: &str,
+    order: &OrderMessage,
     currency: &str,
-    required_amount: Decimal,
-) -> anyhow::Result<bool> {
-    let txn = db.begin().await?;
-
-    // Use raw SQL for FOR UPDATE lock
-    let stmt = sea_orm::Statement::from_sql_and_values(
-        sea_orm::DatabaseBackend::Postgres,
-        r#"SELECT user_id, currency, balance, locked FROM balances 
-           WHERE user_id = $1 AND currency = $2 FOR UPDATE"#,
-        vec![sea_orm::Value::String(Some(Box::new(user_id.to_string()))), sea_orm::Value::String(Some(Box::new(currency.to_string())))],
-    );
+    locked_amount: Decimal,
+) -> Result<PlaceOrderOutcome, CexError> {
+    if let Some(existing) = find_client_order(db, &order.user_id, order.client_order_id.as_deref()).await? {
+        return Ok(PlaceOrderOutcome::Duplicate(Box::new(existing)));
+    }
 
-    let result: Option<BalanceRow> = BalanceRow::find_by_statement(stmt)
-        .one(&txn)
-        .await?;
+    let txn = db.begin().await?;
 
-    match result {
-        Some(balance) => {
-            let available = balance.balance - balance.locked;
-            if available >= required_amount {
-                // Lock the amount using entity update
-                let balance_entity = Balance::find()
-                    .filter(BalanceColumn::UserId.eq(user_id))
-                    .filter(BalanceColumn::Currency.eq(currency))
-                    .one(&txn)
-                    .await?
-                    .ok_or_else(|| anyhow::anyhow!("Balance not found"))?;
-
-                let mut balance: BalanceActiveModel = balance_entity.into();
-                balance.locked = Set(balance.locked.as_ref() + required_amount);
-                balance.update(&txn).await?;
-
-                txn.commit().await?;
-                Ok(true)
-            } else {
-                txn.rollback().await?;
-                Ok(false)
+    if !lock_balance(&txn, &order.user_id, currency, locked_amount).await? {
+        let available = Balance::find_by_id((order.user_id.clone(), currency.to_string()))
+            .one(&txn)
+            .await?
+            .map_or(Decimal::ZERO, |b| b.balance - b.locked);
+        txn.rollback().await?;
+        return Err(CexError::InsufficientBalance {
+            required: format!("{} {}", locked_amount, currency),
+            available: format!("{} {}", available, currency),
+        });
+    }
+    if let Err(e) = create_order_record(&txn, order, locked_amount).await {
+        txn.rollback().await?;
+        // A concurrent request with the same client_order_id won the race
+        if matches!(e.sql_err(), Some(SqlErr::UniqueConstraintViolation(_))) {
+            if let Some(existing) = find_client_order(db, &order.user_id, order.client_order_id.as_deref()).await? {
+                return Ok(PlaceOrderOutcome::Duplicate(Box::new(existing)));
             }
         }
-        None => {
-            txn.rollback().await?;
-            Ok(false)
-        }
+        return Err(e.into());
     }
+    enqueue_command(&txn, &OrderCommand::New(order.clone())).await?;
+
+    txn.commit().await?;
+    Ok(PlaceOrderOutcome::Placed)
 }
 
-pub async fn create_order_record(
+async fn find_client_order(
     db: &DatabaseConnection,
-    order_id: Uuid,
     user_id: &str,
-    pair: &str,
-    order_type: &OrderType,
-    rate: Decimal,
-    amount: Decimal,
-) -> anyhow::Result<()> {
-    let order_type_str = match order_type {
-        OrderType::Buy => "buy",
-        OrderType::Sell => "sell",
+    client_order_id: Option<&str>,
+) -> Result<Option<OrderModel>, CexError> {
+    let Some(client_order_id) = client_order_id else {
+        return Ok(None);
     };
 
+    let order = OrderEntity::find()
+        .filter(OrderColumn::UserId.eq(user_id))
+        .filter(OrderColumn::ClientOrderId.eq(client_order_id))
+        .one(db)
+        .await?;
+    Ok(order)
+}
+
+/// Adds `amount` to the balance's `locked` if enough of it is available. The conditional update
+/// checks and locks in one statement, holding the row lock until the transaction ends.
+async fn lock_balance(
+    txn: &DatabaseTransaction,
+    user_id: &str,
+    currency: &str,
+    amount: Decimal,
+) -> Result<bool, CexError> {
+    let stmt = sea_orm::Statement::from_sql_and_values(
+        sea_orm::DatabaseBackend::Postgres,
+        r#"UPDATE balances SET locked = locked + $3
+           WHERE user_id = $1 AND currency = $2 AND balance - locked >= $3"#,
+        vec![user_id.into(), currency.into(), amount.into()],
+    );
+
+    let result = txn.execute(stmt).await?;
+    Ok(result.rows_affected() == 1)
+}
+
+async fn create_order_record<C: ConnectionTrait>(
+    db: &C,
+    order: &OrderMessage,
+    locked_amount: Decimal,
+) -> Result<(), DbErr> {
     let order = OrderActiveModel {
-        id: Set(order_id),
-        user_id: Set(user_id.to_string()),
-        pair: Set(pair.to_string()),
-        order_type: Set(order_type_str.to_string()),
-        rate: Set(rate),
-        amount: Set(amount),
-        remaining_amount: Set(amount),
+        id: Set(order.order_id),
+        user_id: Set(order.user_id.clone()),
+        pair: Set(order.pair.clone()),
+        order_type: Set(order.order_type.as_str().to_string()),
+        rate: Set(order.rate),
+        amount: Set(order.amount),
+        remaining_amount: Set(order.amount),
+        locked_amount: Set(locked_amount),
+        time_in_force: Set(order.time_in_force.as_str().to_string()),
         status: Set("pending".to_string()),
+        client_order_id: Set(order.client_order_id.clone()),
         executed_at: Set(None),
-        created_at: Set(chrono::Utc::now()),
-        updated_at: Set(chrono::Utc::now()),
+        created_at: Set(order.created_at),
+        updated_at: Set(order.created_at),
     };
 
     order.insert(db).await?;
@@ -97,54 +121,248 @@ pub async fn create_order_record(
     Ok(())
 }
 
-pub async fn get_pending_orders(db: &DatabaseConnection, pair: &str) -> anyhow::Result<Vec<Order>> {
+/// Queues a command for the outbox relay to publish to the `orders` topic.
+pub async fn enqueue_command<C: ConnectionTrait>(db: &C, command: &OrderCommand) -> Result<(), CexError> {
+    let entry = OutboxActiveModel {
+        payload: Set(command.to_json()?),
+        created_at: Set(Utc::now()),
+        sent_at: Set(None),
+        ..Default::default()
+    };
+
+    entry.insert(db).await?;
+
+    Ok(())
+}
+
+pub async fn get_balances(db: &DatabaseConnection, user_id: &str) -> Result<Vec<BalanceModel>, CexError> {
+    let balances = Balance::find()
+        .filter(BalanceColumn::UserId.eq(user_id))
+        .order_by(BalanceColumn::Currency, sea_orm::Order::Asc)
+        .all(db)
+        .await?;
+    Ok(balances)
+}
+
+pub async fn find_user(db: &DatabaseConnection, user_id: &str) -> Result<Option<UserModel>, CexError> {
+    let user = User::find_by_id(user_id.to_string()).one(db).await?;
+    Ok(user)
+}
+
+pub async fn find_user_by_email(db: &DatabaseConnection, email: &str) -> Result<Option<UserModel>, CexError> {
+    let user = User::find()
+        .filter(UserColumn::Email.eq(email))
+        .one(db)
+        .await?;
+    Ok(user)
+}
+
+/// Creates a user together with its first API key.
+pub async fn create_account(
+    db: &DatabaseConnection,
+    user_id: &str,
+    email: &str,
+    api_key: ApiKeyActiveModel,
+) -> Result<(), CexError> {
+    let txn = db.begin().await?;
+
+    let now = chrono::Utc::now();
+    let user = UserActiveModel {
+        id: Set(user_id.to_string()),
+        email: Set(email.to_string()),
+        status: Set(AccountStatus::Active.as_str().to_string()),
+        created_at: Set(now),
+        updated_at: Set(now),
+    };
+    if let Err(e) = user.insert(&txn).await {
+        txn.rollback().await?;
+        // A concurrent sign-up with the same email won the race
+        if matches!(e.sql_err(), Some(SqlErr::UniqueConstraintViolation(_))) {
+            return Err(CexError::Conflict("Email is already registered".to_string()));
+        }
+        return Err(e.into());
+    }
+    api_key.insert(&txn).await?;
+
+    txn.commit().await?;
+
+    Ok(())
+}
+
+pub async fn create_api_key(db: &DatabaseConnection, api_key: ApiKeyActiveModel) -> Result<(), CexError> {
+    api_key.insert(db).await?;
+    Ok(())
+}
+
+pub async fn find_api_key(db: &DatabaseConnection, access_key: &str) -> Result<Option<ApiKeyModel>, CexError> {
+    let api_key = ApiKey::find_by_id(access_key.to_string()).one(db).await?;
+    Ok(api_key)
+}
+
+/// Records `nonce` as the key's latest nonce. Returns false if it is not greater than the last one.
+pub async fn advance_nonce(db: &DatabaseConnection, access_key: &str, nonce: i64) -> Result<bool, CexError> {
+    let stmt = sea_orm::Statement::from_sql_and_values(
+        sea_orm::DatabaseBackend::Postgres,
+        r#"UPDATE api_keys SET last_nonce = $1
+           WHERE access_key = $2 AND last_nonce < $1"#,
+        vec![nonce.into(), access_key.into()],
+    );
+
+    let result = db.execute(stmt).await?;
+    Ok(result.rows_affected() == 1)
+}
+
+pub async fn find_order(db: &DatabaseConnection, order_id: Uuid) -> Result<Option<OrderModel>, CexError> {
+    let order = OrderEntity::find_by_id(order_id).one(db).await?;
+    Ok(order)
+}
+
+/// Filters for a user's order history. Unset fields match every order.
+#[derive(Debug, Default)]
+pub struct OrderFilter {
+    pub pair: Option<String>,
+    pub status: Option<OrderStatus>,
+    /// `true` for buy and market_buy orders, `false` for sell and market_sell orders
+    pub is_buy: Option<bool>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: u64,
+    pub offset: u64,
+}
+
+pub async fn get_open_orders(db: &DatabaseConnection, user_id: &str) -> Result<Vec<Order>, CexError> {
     let orders = OrderEntity::find()
-        .filter(OrderColumn::Pair.eq(pair))
+        .filter(OrderColumn::UserId.eq(user_id))
         .filter(
             OrderColumn::Status.is_in(vec!["pending", "partially_filled"])
         )
-        .order_by(OrderColumn::Rate, sea_orm::Order::Desc)
-        .order_by(OrderColumn::CreatedAt, sea_orm::Order::Asc)
+        .order_by(OrderColumn::CreatedAt, sea_orm::Order::Desc)
         .all(db)
         .await?;
 
-    let result = orders
-        .into_iter()
-        .map(|o| {
-            let order_type = match o.order_type.as_str() {
-                "buy" => OrderType::Buy,
-                "sell" => OrderType::Sell,
-                _ => unreachable!(),
-            };
-            let status = match o.status.as_str() {
-                "pending" => OrderStatus::Pending,
-                "partially_filled" => OrderStatus::PartiallyFilled,
-                "filled" => OrderStatus::Filled,
-                "cancelled" => OrderStatus::Cancelled,
-                _ => OrderStatus::Pending,
-            };
-
-            Order {
-                id: o.id,
-                pair: o.pair,
-                order_type,
-                rate: o.rate,
-                amount: o.amount,
-                remaining_amount: o.remaining_amount,
-                status,
-                created_at: o.created_at,
-            }
-        })
-        .collect();
+    Ok(orders.into_iter().map(Order::from).collect())
+}
 
-    Ok(result)
+pub async fn get_order_history(
+    db: &DatabaseConnection,
+    user_id: &str,
+    filter: &OrderFilter,
+) -> Result<Vec<Order>, CexError> {
+    let mut query = OrderEntity::find().filter(OrderColumn::UserId.eq(user_id));
+
+    if let Some(pair) = &filter.pair {
+        query = query.filter(OrderColumn::Pair.eq(pair.as_str()));
+    }
+    if let Some(status) = &filter.status {
+        query = query.filter(OrderColumn::Status.eq(status_str(status)));
+    }
+    if let Some(is_buy) = filter.is_buy {
+        let order_types = if is_buy {
+            [OrderType::Buy, OrderType::MarketBuy]
+        } else {
+            [OrderType::Sell, OrderType::MarketSell]
+        };
+        query = query.filter(OrderColumn::OrderType.is_in(order_types.iter().map(|t| t.as_str())));
+    }
+    if let Some(from) = filter.from {
+        query = query.filter(OrderColumn::CreatedAt.gte(from));
+    }
+    if let Some(to) = filter.to {
+        query = query.filter(OrderColumn::CreatedAt.lt(to));
+    }
+
+    let orders = query
+        .order_by(OrderColumn::CreatedAt, sea_orm::Order::Desc)
+        .limit(filter.limit)
+        .offset(filter.offset)
+        .all(db)
+        .await?;
+
+    Ok(orders.into_iter().map(Order::from).collect())
+}
+
+/// Fills in which the user was maker or taker, newest first.
+pub async fn get_user_trades(
+    db: &DatabaseConnection,
+    user_id: &str,
+    limit: u64,
+    offset: u64,
+) -> Result<Vec<TradeModel>, CexError> {
+    let trades = Trade::find()
+        .filter(
+            Condition::any()
+                .add(TradeColumn::MakerUserId.eq(user_id))
+                .add(TradeColumn::TakerUserId.eq(user_id)),
+        )
+        .order_by(TradeColumn::CreatedAt, sea_orm::Order::Desc)
+        .limit(limit)
+        .offset(offset)
+        .all(db)
+        .await?;
+    Ok(trades)
+}
+
+pub async fn get_public_trades(db: &DatabaseConnection, pair: &str, limit: u64) -> Result<Vec<TradeModel>, CexError> {
+    let trades = Trade::find()
+        .filter(TradeColumn::Pair.eq(pair))
+        .order_by(TradeColumn::CreatedAt, sea_orm::Order::Desc)
+        .limit(limit)
+        .all(db)
+        .await?;
+    Ok(trades)
+}
+
+/// Fills of every pair since `since`, oldest first.
+pub async fn get_trades_since(db: &DatabaseConnection, since: DateTime<Utc>) -> Result<Vec<TradeModel>, CexError> {
+    let trades = Trade::find()
+        .filter(TradeColumn::CreatedAt.gte(since))
+        .order_by(TradeColumn::CreatedAt, sea_orm::Order::Asc)
+        .all(db)
+        .await?;
+    Ok(trades)
+}
+
+/// Candles of a pair opening between `from` and `to`, oldest first.
+pub async fn get_candles(
+    db: &DatabaseConnection,
+    pair: &str,
+    interval: CandleInterval,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<CandleModel>, CexError> {
+    let candles = CandleEntity::find()
+        .filter(CandleColumn::Pair.eq(pair))
+        .filter(CandleColumn::Interval.eq(interval.as_str()))
+        .filter(CandleColumn::OpenTime.gte(from))
+        .filter(CandleColumn::OpenTime.lte(to))
+        .order_by(CandleColumn::OpenTime, sea_orm::Order::Asc)
+        .all(db)
+        .await?;
+    Ok(candles)
+}
+
+/// The newest candle of a pair opening before `before`, whose close carries forward.
+pub async fn get_candle_before(
+    db: &DatabaseConnection,
+    pair: &str,
+    interval: CandleInterval,
+    before: DateTime<Utc>,
+) -> Result<Option<CandleModel>, CexError> {
+    let candle = CandleEntity::find()
+        .filter(CandleColumn::Pair.eq(pair))
+        .filter(CandleColumn::Interval.eq(interval.as_str()))
+        .filter(CandleColumn::OpenTime.lt(before))
+        .order_by(CandleColumn::OpenTime, sea_orm::Order::Desc)
+        .one(db)
+        .await?;
+    Ok(candle)
 }
 
 pub async fn get_executed_orders(
     db: &DatabaseConnection,
     limit: i64,
     offset: i64,
-) -> anyhow::Result<Vec<Order>> {
+) -> Result<Vec<Order>, CexError> {
     let orders = OrderEntity::find()
         .filter(OrderColumn::ExecutedAt.is_not_null())
         .order_by(OrderColumn::ExecutedAt, sea_orm::Order::Desc)
@@ -155,32 +373,17 @@ pub async fn get_executed_orders(
 
     let result = orders
         .into_iter()
-        .map(|o| {
-            let order_type = match o.order_type.as_str() {
-                "buy" => OrderType::Buy,
-                "sell" => OrderType::Sell,
-                _ => unreachable!(),
-            };
-            let status = match o.status.as_str() {
-                "pending" => OrderStatus::Pending,
-                "partially_filled" => OrderStatus::PartiallyFilled,
-                "filled" => OrderStatus::Filled,
-                "cancelled" => OrderStatus::Cancelled,
-                _ => OrderStatus::Pending,
-            };
-
-            Order {
-                id: o.id,
-                pair: o.pair,
-                order_type,
-                rate: o.rate,
-                amount: o.amount,
-                remaining_amount: o.remaining_amount,
-                status,
-                created_at: o.created_at,
-            }
-        })
+        .map(Order::from)
         .collect();
 
     Ok(result)
 }
+
+fn status_str(status: &OrderStatus) -> &'static str {
+    match status {
+        OrderStatus::Pending => "pending",
+        OrderStatus::PartiallyFilled => "partially_filled",
+        OrderStatus::Filled => "filled",
+        OrderStatus::Cancelled => "cancelled",
+    }
+}
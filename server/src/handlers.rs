@@ -1,17 +1,33 @@
-use axum::{
-    extract::{Query, State},
-    http::StatusCode,
-    response::Json,
-};
+use axum::extract::{Extension, State};
 use shared::{
-    CreateOrderRequest, Order, OrderBook, OrderBookEntry, OrderMessage, OrderType,
+    AccountStatus, BalanceResponse, CancelOrderMessage, Candle, CexError, CandleInterval, CreateAccountRequest, CreateApiKeyRequest, CreateOrderRequest, Order, OrderBook, OrderCommand,
+    OrderMessage, OrderStatus, OrderType, PublicTrade, TimeInForce,
+    Transaction,
 };
 use rust_decimal::Decimal;
+use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+
+use crate::{
+    auth::{self, AuthenticatedUser, Permission},
+    db,
+    extract::{Json, Path, Query},
+    order_book, AppState,
+};
 
-use crate::{db, AppState};
+// Candles returned by one candles request
+const MAX_CANDLES: i64 = 1000;
+
+#[derive(Deserialize)]
+pub struct OrderBookQuery {
+    pair: Option<String>,
+    /// Price levels per side, 50 by default
+    depth: Option<usize>,
+    /// 2 for price levels with their order count (default), 3 for individual orders
+    level: Option<u8>,
+}
 
 #[derive(Deserialize)]
 pub struct ExecutedOrderQuery {
@@ -19,133 +35,627 @@ pub struct ExecutedOrderQuery {
     offset: Option<i64>,
 }
 
+#[derive(Deserialize)]
+pub struct OrderHistoryQuery {
+    pair: Option<String>,
+    status: Option<OrderStatus>,
+    /// `buy` or `sell`, market orders are included with their side
+    side: Option<String>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    limit: Option<u64>,
+    offset: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct TransactionQuery {
+    limit: Option<u64>,
+    offset: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct TradeQuery {
+    pair: String,
+    limit: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct TickerQuery {
+    pair: Option<String>,
+}
+
+/// Coincheck's ticker: last price, best bid and ask, and the 24 hour high, low and volume.
+/// Prices are `null` while there is no such price.
+#[derive(Serialize)]
+pub struct TickerResponse {
+    pub last: Option<Decimal>,
+    pub bid: Option<Decimal>,
+    pub ask: Option<Decimal>,
+    pub high: Option<Decimal>,
+    pub low: Option<Decimal>,
+    pub volume: Decimal,
+    pub timestamp: i64,
+}
+
+#[derive(Deserialize)]
+pub struct CandleQuery {
+    pair: String,
+    interval: CandleInterval,
+    /// Defaults to 100 candles before `to`
+    from: Option<DateTime<Utc>>,
+    /// Defaults to now
+    to: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct CandlesResponse {
+    pub success: bool,
+    pub data: Vec<Candle>,
+}
+
+#[derive(Serialize)]
+pub struct TransactionsResponse {
+    pub success: bool,
+    pub transactions: Vec<Transaction>,
+}
+
+#[derive(Serialize)]
+pub struct PublicTradesResponse {
+    pub success: bool,
+    pub data: Vec<PublicTrade>,
+}
+
+#[derive(Serialize)]
+pub struct OrdersResponse {
+    pub success: bool,
+    pub orders: Vec<Order>,
+}
+
+#[derive(Serialize)]
+pub struct OrderResponse {
+    pub success: bool,
+    pub order: Order,
+}
+
 #[derive(Serialize)]
 pub struct CreateOrderResponse {
     pub order_id: Uuid,
+    pub client_order_id: Option<String>,
     pub success: bool,
 }
 
+#[derive(Serialize)]
+pub struct CancelOrderResponse {
+    pub id: Uuid,
+    pub success: bool,
+}
+
+#[derive(Serialize)]
+pub struct CreateAccountResponse {
+    pub success: bool,
+    pub user_id: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+#[derive(Serialize)]
+pub struct ApiKeyResponse {
+    pub success: bool,
+    pub access_key: String,
+    pub secret_key: String,
+    pub can_trade: bool,
+    pub can_read: bool,
+    pub can_withdraw: bool,
+}
+
+/// Registers a new user and returns its first API key, allowed to trade and read.
+pub async fn create_account(
+    State(state): State<AppState>,
+    Json(req): Json<CreateAccountRequest>,
+) -> Result<Json<CreateAccountResponse>, CexError> {
+    let email = req.email.trim().to_lowercase();
+    if !email.contains('@') {
+        return Err(CexError::InvalidRequest("Invalid email".to_string()));
+    }
+
+    let existing = db::find_user_by_email(&state.db, &email).await?;
+    if existing.is_some() {
+        return Err(CexError::Conflict("Email is already registered".to_string()));
+    }
+
+    let user_id = Uuid::new_v4().to_string();
+    let api_key = auth::new_api_key(&user_id, true, true, false);
+    let access_key = api_key.access_key.as_ref().clone();
+    let secret_key = api_key.secret_key.as_ref().clone();
+
+    db::create_account(&state.db, &user_id, &email, api_key).await?;
+
+    Ok(Json(CreateAccountResponse {
+        success: true,
+        user_id,
+        access_key,
+        secret_key,
+    }))
+}
+
+/// Issues an additional API key for the caller. A key can only grant permissions it holds itself.
+pub async fn create_api_key(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    Json(req): Json<CreateApiKeyRequest>,
+) -> Result<Json<ApiKeyResponse>, CexError> {
+    if req.can_trade {
+        user.require(Permission::Trade)?;
+    }
+    if req.can_read {
+        user.require(Permission::Read)?;
+    }
+    if req.can_withdraw {
+        user.require(Permission::Withdraw)?;
+    }
+
+    require_active_account(&state, &user.user_id).await?;
+
+    let api_key = auth::new_api_key(&user.user_id, req.can_trade, req.can_read, req.can_withdraw);
+    let access_key = api_key.access_key.as_ref().clone();
+    let secret_key = api_key.secret_key.as_ref().clone();
+
+    db::create_api_key(&state.db, api_key).await?;
+
+    Ok(Json(ApiKeyResponse {
+        success: true,
+        access_key,
+        secret_key,
+        can_trade: req.can_trade,
+        can_read: req.can_read,
+        can_withdraw: req.can_withdraw,
+    }))
+}
+
+pub async fn get_balance(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+) -> Result<Json<BalanceResponse>, CexError> {
+    user.require(Permission::Read)?;
+
+    let rows = db::get_balances(&state.db, &user.user_id).await?;
+
+    // Report every listed currency, even those the user has never held
+    let mut balances = HashMap::new();
+    for pair in state.pairs.iter() {
+        for currency in [&pair.base_currency, &pair.quote_currency] {
+            let currency = currency.to_lowercase();
+            balances.insert(format!("{}_reserved", currency), Decimal::ZERO);
+            balances.insert(format!("{}_total", currency), Decimal::ZERO);
+            balances.insert(currency, Decimal::ZERO);
+        }
+    }
+
+    for row in rows {
+        let currency = row.currency.to_lowercase();
+        balances.insert(format!("{}_reserved", currency), row.locked);
+        balances.insert(format!("{}_total", currency), row.balance);
+        balances.insert(currency, row.balance - row.locked);
+    }
+
+    Ok(Json(BalanceResponse {
+        success: true,
+        balances,
+    }))
+}
+
+async fn require_active_account(state: &AppState, user_id: &str) -> Result<(), CexError> {
+    let user = db::find_user(&state.db, user_id).await?
+        .ok_or_else(|| CexError::Forbidden("Account not found".to_string()))?;
+
+    let status: AccountStatus = user
+        .status
+        .parse()
+        .expect("status is constrained by the users table");
+    if status != AccountStatus::Active {
+        return Err(CexError::Forbidden(format!("Account is {}", status.as_str())));
+    }
+
+    Ok(())
+}
+
 pub async fn create_order(
     State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
     Json(req): Json<CreateOrderRequest>,
-) -> Result<Json<CreateOrderResponse>, (StatusCode, String)> {
+) -> Result<Json<CreateOrderResponse>, CexError> {
+    user.require(Permission::Trade)?;
+
     // Validate request
-    if req.rate <= Decimal::ZERO || req.amount <= Decimal::ZERO {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Rate and amount must be positive".to_string(),
+    let (rate, amount) = match req.order_type {
+        OrderType::Buy | OrderType::Sell => match (req.rate, req.amount) {
+            (Some(rate), Some(amount)) if rate > Decimal::ZERO && amount > Decimal::ZERO => {
+                (Some(rate), amount)
+            }
+            _ => {
+                return Err(CexError::InvalidOrder(
+                    "Rate and amount must be positive".to_string(),
+                ));
+            }
+        },
+        OrderType::MarketBuy => match req.market_buy_amount {
+            Some(amount) if amount > Decimal::ZERO => (None, amount),
+            _ => {
+                return Err(CexError::InvalidOrder(
+                    "market_buy_amount must be positive".to_string(),
+                ));
+            }
+        },
+        OrderType::MarketSell => match req.amount {
+            Some(amount) if amount > Decimal::ZERO => (None, amount),
+            _ => {
+                return Err(CexError::InvalidOrder(
+                    "Amount must be positive".to_string(),
+                ));
+            }
+        },
+    };
+
+    if req
+        .client_order_id
+        .as_ref()
+        .is_some_and(|id| id.is_empty() || id.len() > 64)
+    {
+        return Err(CexError::InvalidOrder(
+            "client_order_id must be 1 to 64 characters".to_string(),
         ));
     }
 
-    if req.pair != "btc_jpy" {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Only btc_jpy pair is supported".to_string(),
+    if req.order_type.is_market() && req.time_in_force == TimeInForce::PostOnly {
+        return Err(CexError::InvalidOrder(
+            "Market orders cannot be post_only".to_string(),
         ));
     }
 
-    // No authentication in MVC implementation, use default user
-    let user_id = "default_user";
-    let order_id = Uuid::new_v4();
+    let pair = state
+        .pairs
+        .active(&req.pair)
+        .ok_or_else(|| CexError::PairNotSupported(req.pair.clone()))?;
+    pair.validate_order(&req.order_type, rate, amount)?;
 
-    // Check and lock balance
-    let (currency, required_amount) = match req.order_type {
-        OrderType::Buy => ("JPY", req.rate * req.amount),
-        OrderType::Sell => ("BTC", req.amount),
-    };
+    let user_id = user.user_id.as_str();
+    let order_id = Uuid::new_v4();
 
-    let has_balance = db::check_and_lock_balance(&state.db, user_id, currency, required_amount)
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)))?;
+    // Frozen and closed accounts cannot lock balance for new orders
+    require_active_account(&state, user_id).await?;
 
-    if !has_balance {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            format!("Insufficient balance for {}", currency),
-        ));
-    }
+    // Check and lock balance: buys lock the quote currency, sells the base currency
+    let currency = pair.locked_currency(req.order_type.is_buy());
+    let required_amount = match (&req.order_type, rate) {
+        (OrderType::Buy, Some(rate)) => rate * amount,
+        // Market buys lock exactly the quote amount they may spend
+        _ => amount,
+    };
 
-    // Create order record in DB
-    db::create_order_record(
-        &state.db,
-        order_id,
-        user_id,
-        &req.pair,
-        &req.order_type,
-        req.rate,
-        req.amount,
-    )
-    .await
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)))?;
-
-    // Send order to Kafka
     let order_message = OrderMessage {
         order_id,
         user_id: user_id.to_string(),
         pair: req.pair.clone(),
         order_type: req.order_type.clone(),
-        rate: req.rate,
-        amount: req.amount,
+        rate,
+        amount,
+        time_in_force: req.time_in_force.clone(),
+        client_order_id: req.client_order_id.clone(),
         created_at: Utc::now(),
     };
 
-    state
-        .kafka_producer
-        .send_order(order_message)
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Kafka error: {}", e)))?;
+    // Lock the balance and record the order, the outbox relay sends it to the matcher
+    let outcome = db::place_order(&state.db, &order_message, currency, required_amount).await?;
+
+    match outcome {
+        db::PlaceOrderOutcome::Placed => Ok(Json(CreateOrderResponse {
+            order_id,
+            client_order_id: order_message.client_order_id,
+            success: true,
+        })),
+        // A retried request, answer with the order the first attempt created
+        db::PlaceOrderOutcome::Duplicate(existing) => Ok(Json(CreateOrderResponse {
+            order_id: existing.id,
+            client_order_id: existing.client_order_id,
+            success: true,
+        })),
+    }
+}
 
-    Ok(Json(CreateOrderResponse {
+pub async fn cancel_order(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    Path(order_id): Path<Uuid>,
+) -> Result<Json<CancelOrderResponse>, CexError> {
+    user.require(Permission::Trade)?;
+    let user_id = user.user_id.as_str();
+
+    let order = db::find_order(&state.db, order_id).await?
+        .filter(|o| o.user_id == user_id)
+        .ok_or(CexError::OrderNotFound)?;
+
+    if order.status != "pending" && order.status != "partially_filled" {
+        return Err(CexError::OrderNotOpen(order.status));
+    }
+
+    // The matcher removes the order from the book and settlement releases the locked balance
+    let cancel_message = CancelOrderMessage {
         order_id,
+        user_id: user_id.to_string(),
+        pair: order.pair,
+        created_at: Utc::now(),
+    };
+
+    db::enqueue_command(&state.db, &OrderCommand::Cancel(cancel_message)).await?;
+
+    Ok(Json(CancelOrderResponse {
+        id: order_id,
         success: true,
     }))
 }
 
-pub async fn get_order_books(
+pub async fn get_open_orders(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+) -> Result<Json<OrdersResponse>, CexError> {
+    user.require(Permission::Read)?;
+
+    let orders = db::get_open_orders(&state.db, &user.user_id).await?;
+
+    Ok(Json(OrdersResponse {
+        success: true,
+        orders,
+    }))
+}
+
+pub async fn get_order(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    Path(order_id): Path<Uuid>,
+) -> Result<Json<OrderResponse>, CexError> {
+    user.require(Permission::Read)?;
+
+    let order = db::find_order(&state.db, order_id).await?
+        .filter(|o| o.user_id == user.user_id)
+        .ok_or(CexError::OrderNotFound)?;
+
+    Ok(Json(OrderResponse {
+        success: true,
+        order: Order::from(order),
+    }))
+}
+
+pub async fn get_order_history(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthenticatedUser>,
+    Query(params): Query<OrderHistoryQuery>,
+) -> Result<Json<OrdersResponse>, CexError> {
+    user.require(Permission::Read)?;
+
+    let is_buy = match params.side.as_deref() {
+        None => None,
+        Some("buy") => Some(true),
+        Some("sell") => Some(false),
+        Some(side) => {
+            return Err(CexError::InvalidRequest(format!(
+                "Unknown side {}, expected buy or sell",
+                side
+            )));
+        }
+    };
+
+    let filter = db::OrderFilter {
+        pair: params.pair,
+        status: params.status,
+        is_buy,
+        from: params.from,
+        to: params.to,
+        limit: params.limit.unwrap_or(100).min(1000),
+        offset: params.offset.unwrap_or(0),
+    };
+
+    let orders = db::get_order_history(&state.db, &user.user_id, &filter).await?;
+
+    Ok(Json(OrdersResponse {
+        success: true,
+        orders,
+    }))
+}
+
+pub async fn get_transactions(
     State(state): State<AppState>,
-) -> Result<Json<OrderBook>, (StatusCode, String)> {
-    // Get pending orders from DB
-    let orders = db::get_pending_orders(&state.db, "btc_jpy")
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)))?;
-
-    // Separate into bids and asks
-    let mut bids: Vec<OrderBookEntry> = Vec::new();
-    let mut asks: Vec<OrderBookEntry> = Vec::new();
-
-    for order in orders {
-        let entry = OrderBookEntry {
-            price: order.rate,
-            amount: order.remaining_amount,
-        };
-
-        match order.order_type {
-            OrderType::Buy => bids.push(entry),
-            OrderType::Sell => asks.push(entry),
+    Extension(user): Extension<AuthenticatedUser>,
+    Query(params): Query<TransactionQuery>,
+) -> Result<Json<TransactionsResponse>, CexError> {
+    user.require(Permission::Read)?;
+
+    let limit = params.limit.unwrap_or(100).min(1000);
+    let offset = params.offset.unwrap_or(0);
+
+    let trades = db::get_user_trades(&state.db, &user.user_id, limit, offset).await?;
+
+    let mut transactions = Vec::new();
+    for trade in &trades {
+        let pair = state
+            .pairs
+            .get(&trade.pair)
+            .ok_or_else(|| CexError::Internal(format!("Pair {} not found", trade.pair)))?;
+        // A self-trade shows up once as maker and once as taker
+        if trade.maker_user_id == user.user_id {
+            transactions.push(Transaction::from_trade(trade, pair, false));
+        }
+        if trade.taker_user_id == user.user_id {
+            transactions.push(Transaction::from_trade(trade, pair, true));
         }
     }
 
-    // Sort bids descending (highest first), asks ascending (lowest first)
-    bids.sort_by(|a, b| b.price.cmp(&a.price));
-    asks.sort_by(|a, b| a.price.cmp(&b.price));
+    Ok(Json(TransactionsResponse {
+        success: true,
+        transactions,
+    }))
+}
+
+pub async fn get_ticker(
+    State(state): State<AppState>,
+    Query(params): Query<TickerQuery>,
+) -> Result<Json<TickerResponse>, CexError> {
+    let pair = params.pair.unwrap_or_else(|| "btc_jpy".to_string());
+    if state.pairs.get(&pair).is_none() {
+        return Err(CexError::PairNotSupported(pair));
+    }
 
-    Ok(Json(OrderBook {
-        pair: "btc_jpy".to_string(),
-        bids,
-        asks,
+    // Best prices from the matcher's latest book snapshot
+    let (bid, ask) = match state.order_books.read().await.get(&pair) {
+        Some(snapshot) => (
+            snapshot.bids.first().map(|level| level.rate),
+            snapshot.asks.first().map(|level| level.rate),
+        ),
+        None => (None, None),
+    };
+
+    let now = Utc::now();
+    let (last, high, low, volume) = match state.ticker_stats.write().await.get_mut(&pair) {
+        Some(stats) => stats.summary(now),
+        None => (None, None, None, Decimal::ZERO),
+    };
+
+    Ok(Json(TickerResponse {
+        last,
+        bid,
+        ask,
+        high,
+        low,
+        volume,
+        timestamp: now.timestamp(),
     }))
 }
 
+pub async fn get_candles(
+    State(state): State<AppState>,
+    Query(params): Query<CandleQuery>,
+) -> Result<Json<CandlesResponse>, CexError> {
+    if state.pairs.get(&params.pair).is_none() {
+        return Err(CexError::PairNotSupported(params.pair));
+    }
+
+    let interval = params.interval;
+    let step = chrono::Duration::seconds(interval.seconds());
+    let to = interval.open_time(params.to.unwrap_or_else(Utc::now));
+    let from = interval.open_time(params.from.unwrap_or(to - step * 99));
+    if from > to {
+        return Err(CexError::InvalidRequest("from must not be after to".to_string()));
+    }
+    if (to - from).num_seconds() / interval.seconds() >= MAX_CANDLES {
+        return Err(CexError::InvalidRequest(format!(
+            "At most {} candles can be requested at once",
+            MAX_CANDLES
+        )));
+    }
+
+    let candles = db::get_candles(&state.db, &params.pair, interval, from, to).await?;
+    let previous = db::get_candle_before(&state.db, &params.pair, interval, from).await?;
+
+    // Intervals without fills repeat the previous close, those before the first fill are left out
+    let mut candles = candles.into_iter().peekable();
+    let mut last_close = previous.map(|candle| candle.close);
+    let mut data = Vec::new();
+    let mut open_time = from;
+    while open_time <= to {
+        match candles.next_if(|candle| candle.open_time == open_time) {
+            Some(candle) => {
+                last_close = Some(candle.close);
+                data.push(Candle {
+                    open_time,
+                    open: candle.open,
+                    high: candle.high,
+                    low: candle.low,
+                    close: candle.close,
+                    volume: candle.volume,
+                });
+            }
+            None => {
+                if let Some(close) = last_close {
+                    data.push(Candle {
+                        open_time,
+                        open: close,
+                        high: close,
+                        low: close,
+                        close,
+                        volume: Decimal::ZERO,
+                    });
+                }
+            }
+        }
+        open_time += step;
+    }
+
+    Ok(Json(CandlesResponse {
+        success: true,
+        data,
+    }))
+}
+
+pub async fn get_trades(
+    State(state): State<AppState>,
+    Query(params): Query<TradeQuery>,
+) -> Result<Json<PublicTradesResponse>, CexError> {
+    if state.pairs.get(&params.pair).is_none() {
+        return Err(CexError::PairNotSupported(params.pair));
+    }
+
+    let limit = params.limit.unwrap_or(100).min(1000);
+    let trades = db::get_public_trades(&state.db, &params.pair, limit).await?;
+
+    let data = trades
+        .into_iter()
+        .map(|t| PublicTrade {
+            order_type: t.taker_side.parse().expect("taker_side is constrained by the trades table"),
+            id: t.id,
+            pair: t.pair,
+            rate: t.rate,
+            amount: t.amount,
+            created_at: t.created_at,
+        })
+        .collect();
+
+    Ok(Json(PublicTradesResponse {
+        success: true,
+        data,
+    }))
+}
+
+pub async fn get_order_books(
+    State(state): State<AppState>,
+    Query(params): Query<OrderBookQuery>,
+) -> Result<Json<OrderBook>, CexError> {
+    let pair = params.pair.unwrap_or_else(|| "btc_jpy".to_string());
+    if state.pairs.get(&pair).is_none() {
+        return Err(CexError::PairNotSupported(pair));
+    }
+
+    let depth = params.depth.unwrap_or(50).clamp(1, 1000);
+    let level = params.level.unwrap_or(2);
+    if level != 2 && level != 3 {
+        return Err(CexError::InvalidRequest("level must be 2 or 3".to_string()));
+    }
+
+    // Served from the matcher's latest book snapshot
+    let order_books = state.order_books.read().await;
+    let snapshot = order_books
+        .get(&pair)
+        .ok_or_else(|| CexError::Unavailable(format!("Order book for {} is not available yet", pair)))?;
+
+    Ok(Json(order_book::view(snapshot, level, depth)))
+}
+
 pub async fn get_executed_orders(
     State(state): State<AppState>,
     Query(params): Query<ExecutedOrderQuery>,
-) -> Result<Json<Vec<Order>>, (StatusCode, String)> {
+) -> Result<Json<Vec<Order>>, CexError> {
     let limit = params.limit.unwrap_or(100).min(1000);
     let offset = params.offset.unwrap_or(0);
 
-    let orders = db::get_executed_orders(&state.db, limit, offset)
-        .await
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)))?;
+    let orders = db::get_executed_orders(&state.db, limit, offset).await?;
 
     Ok(Json(orders))
 }
@@ -0,0 +1,73 @@
+use rdkafka::{
+    config::ClientConfig,
+    consumer::{stream_consumer::StreamConsumer, Consumer},
+    Message, Offset, TopicPartitionList,
+};
+use anyhow::Result;
+use std::collections::HashMap;
+
+pub struct KafkaConsumer {
+    consumer: StreamConsumer,
+}
+
+impl KafkaConsumer {
+    /// Every server instance streams market data to its own clients, so each one consumes under
+    /// its own group id from the latest offset.
+    pub fn new(topics: &[&str]) -> Result<Self> {
+        let consumer = create_consumer("latest")?;
+        consumer.subscribe(topics)?;
+
+        Ok(Self { consumer })
+    }
+
+    /// Consumes the partitions of `topic` from `offsets`, where a backfill from the database
+    /// ended. Without offsets the topic did not exist yet, so everything in it is read once it
+    /// appears.
+    pub fn from_offsets(topic: &str, offsets: &HashMap<i32, i64>) -> Result<Self> {
+        if offsets.is_empty() {
+            let consumer = create_consumer("earliest")?;
+            consumer.subscribe(&[topic])?;
+            return Ok(Self { consumer });
+        }
+
+        let consumer = create_consumer("latest")?;
+        let mut assignment = TopicPartitionList::new();
+        for (&partition, &offset) in offsets {
+            assignment.add_partition_offset(topic, partition, Offset::Offset(offset))?;
+        }
+        consumer.assign(&assignment)?;
+
+        Ok(Self { consumer })
+    }
+
+    /// Returns the topic and payload of the next message.
+    pub async fn consume_message(&self) -> Result<Option<(String, String)>> {
+        match self.consumer.recv().await {
+            Ok(message) => {
+                let payload = message.payload().ok_or_else(|| anyhow::anyhow!("Empty payload"))?;
+                Ok(Some((message.topic().to_string(), std::str::from_utf8(payload)?.to_string())))
+            }
+            Err(_e) => {
+                // Non-fatal errors (like timeout) are expected, return None
+                // Fatal errors will be propagated by the consumer automatically
+                Ok(None)
+            }
+        }
+    }
+}
+
+fn create_consumer(offset_reset: &str) -> Result<StreamConsumer> {
+    let kafka_bootstrap_servers = std::env::var("KAFKA_BOOTSTRAP_SERVERS")
+        .map_err(|_| anyhow::anyhow!("KAFKA_BOOTSTRAP_SERVERS environment variable is required"))?;
+
+    let group_id = format!("server-{}", uuid::Uuid::new_v4().simple());
+    let consumer: StreamConsumer = ClientConfig::new()
+        .set("bootstrap.servers", &kafka_bootstrap_servers)
+        .set("group.id", &group_id)
+        .set("enable.partition.eof", "false")
+        .set("session.timeout.ms", "6000")
+        .set("enable.auto.commit", "false")
+        .set("auto.offset.reset", offset_reset)
+        .create()?;
+    Ok(consumer)
+}
@@ -2,7 +2,7 @@ use rdkafka::{
     config::ClientConfig,
     producer::{FutureProducer, FutureRecord},
 };
-use shared::OrderMessage;
+use shared::OrderCommand;
 use std::time::Duration;
 
 pub struct KafkaProducer {
@@ -22,10 +22,10 @@ impl KafkaProducer {
         Ok(Self { producer })
     }
 
-    pub async fn send_order(&self, order: OrderMessage) -> anyhow::Result<()> {
-        let json = order.to_json()?;
+    pub async fn send_command(&self, command: OrderCommand) -> anyhow::Result<()> {
+        let json = command.to_json()?;
         // Use pair as key to ensure orders for the same pair go to the same partition, guaranteeing order
-        let key = order.pair.clone();
+        let key = command.pair().to_string();
         let record = FutureRecord::to("orders")
             .key(&key)
             .payload(&json);
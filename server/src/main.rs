@@ -1,20 +1,38 @@
+mod auth;
 mod handlers;
+mod kafka_consumer;
 mod kafka_producer;
 mod db;
+mod extract;
+mod order_book;
+mod outbox;
+mod ticker;
+mod websocket;
 
 use axum::{
+    middleware,
     routing::{get, post},
     Router,
 };
 use handlers::*;
 use sea_orm::Database;
+use shared::{OrderEvent, PairRegistry};
 use std::sync::Arc;
+use tokio::sync::broadcast;
 use tower_http::cors::CorsLayer;
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: sea_orm::DatabaseConnection,
-    pub kafka_producer: Arc<kafka_producer::KafkaProducer>,
+    pub pairs: Arc<PairRegistry>,
+    // Public market data for WebSocket clients
+    pub market_feed: broadcast::Sender<websocket::FeedMessage>,
+    // Order and execution events for private WebSocket clients
+    pub order_events: broadcast::Sender<OrderEvent>,
+    // Book snapshots published by the matcher, served by the order book endpoint
+    pub order_books: order_book::OrderBookCache,
+    // Rolling 24 hour trade statistics for the ticker
+    pub ticker_stats: ticker::TickerStats,
 }
 
 #[tokio::main]
@@ -28,19 +46,81 @@ async fn main() -> anyhow::Result<()> {
     
     let db = Database::connect(&database_url).await?;
 
-    // Initialize Kafka producer
+    // Load trading pairs
+    let pairs = Arc::new(PairRegistry::load(&db).await?);
+
+    // Initialize Kafka producer, order commands reach it through the outbox relay
     let kafka_producer = Arc::new(kafka_producer::KafkaProducer::new().await?);
+    tokio::spawn(outbox::run_relay(db.clone(), kafka_producer));
+
+    // Stream order book deltas and trades from the matcher to WebSocket clients
+    let (market_feed, _) = broadcast::channel(1024);
+    let feed = market_feed.clone();
+    tokio::spawn(async move {
+        if let Err(e) = websocket::run_market_feed(feed).await {
+            eprintln!("Market data feed stopped: {}", e);
+        }
+    });
+
+    // Stream settlement's order events to their owners
+    let (order_events, _) = broadcast::channel(1024);
+    let events = order_events.clone();
+    tokio::spawn(async move {
+        if let Err(e) = websocket::run_order_events(events).await {
+            eprintln!("Order event feed stopped: {}", e);
+        }
+    });
+
+    // Cache the matcher's book snapshots for the order book endpoint
+    let order_books = order_book::OrderBookCache::default();
+    let cache = order_books.clone();
+    tokio::spawn(async move {
+        if let Err(e) = order_book::run_cache(cache).await {
+            eprintln!("Order book cache stopped: {}", e);
+        }
+    });
+
+    // Follow fills for the ticker's 24 hour statistics
+    let ticker_stats = ticker::TickerStats::default();
+    let stats = ticker_stats.clone();
+    let stats_db = db.clone();
+    tokio::spawn(async move {
+        if let Err(e) = ticker::run_stats(stats_db, stats).await {
+            eprintln!("Ticker statistics stopped: {}", e);
+        }
+    });
 
     let app_state = AppState {
         db,
-        kafka_producer,
+        pairs,
+        market_feed,
+        order_events,
+        order_books,
+        ticker_stats,
     };
 
+    // Private API, requests must be signed with an API key
+    let private_routes = Router::new()
+        .route("/api/exchange/orders", post(create_order))
+        .route("/api/exchange/orders/opens", get(get_open_orders))
+        .route("/api/exchange/orders/history", get(get_order_history))
+        .route("/api/exchange/orders/transactions", get(get_transactions))
+        .route("/api/exchange/orders/:id", get(get_order).delete(cancel_order))
+        .route("/api/api_keys", post(create_api_key))
+        .route("/api/accounts/balance", get(get_balance))
+        .route_layer(middleware::from_fn_with_state(app_state.clone(), auth::authenticate));
+
     // Build router
     let app = Router::new()
-        .route("/api/exchange/orders", post(create_order))
+        .merge(private_routes)
+        .route("/api/accounts", post(create_account))
         .route("/api/order_books", get(get_order_books))
+        .route("/api/ticker", get(get_ticker))
+        .route("/api/candles", get(get_candles))
+        .route("/api/trades", get(get_trades))
         .route("/api/order_books/executed", get(get_executed_orders))
+        .route("/api/ws", get(websocket::public_ws))
+        .route("/api/ws/private", get(websocket::private_ws))
         .layer(CorsLayer::permissive())
         .with_state(app_state);
 
@@ -0,0 +1,71 @@
+use shared::{BookLevel, OrderBook, OrderBookEntry, OrderBookSnapshot};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::kafka_consumer::KafkaConsumer;
+
+/// Pair -> latest book snapshot published by the matcher.
+pub type OrderBookCache = Arc<RwLock<HashMap<String, OrderBookSnapshot>>>;
+
+/// Keeps the cache up to date with the book snapshots the matcher publishes. The matcher
+/// republishes every book periodically, so a freshly started server fills its cache within
+/// seconds.
+pub async fn run_cache(cache: OrderBookCache) -> anyhow::Result<()> {
+    let consumer = KafkaConsumer::new(&["orderbook-snapshots"])?;
+
+    loop {
+        match consumer.consume_message().await {
+            Ok(Some((_, payload))) => match OrderBookSnapshot::from_json(&payload) {
+                Ok(snapshot) => {
+                    cache.write().await.insert(snapshot.pair.clone(), snapshot);
+                }
+                Err(e) => eprintln!("Error decoding order book snapshot: {}", e),
+            },
+            Ok(None) => {
+                // No message, continue
+                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+            }
+            Err(e) => {
+                eprintln!("Error consuming message: {}", e);
+                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+/// The best `depth` price levels of each side, aggregated per price for level 2 or one entry
+/// per resting order in time priority for level 3.
+pub fn view(snapshot: &OrderBookSnapshot, level: u8, depth: usize) -> OrderBook {
+    let entries = |levels: &[BookLevel]| -> Vec<OrderBookEntry> {
+        let levels = levels.iter().take(depth);
+        if level == 3 {
+            levels
+                .flat_map(|l| {
+                    l.orders.iter().map(|order| OrderBookEntry {
+                        price: l.rate,
+                        amount: order.amount,
+                        order_count: None,
+                        order_id: Some(order.id),
+                    })
+                })
+                .collect()
+        } else {
+            levels
+                .map(|l| OrderBookEntry {
+                    price: l.rate,
+                    amount: l.amount,
+                    order_count: Some(l.order_count),
+                    order_id: None,
+                })
+                .collect()
+        }
+    };
+
+    OrderBook {
+        pair: snapshot.pair.clone(),
+        bids: entries(&snapshot.bids),
+        asks: entries(&snapshot.asks),
+        updated_at: snapshot.created_at,
+    }
+}
@@ -0,0 +1,66 @@
+use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder, QuerySelect, TransactionTrait};
+use shared::{OrderCommand, Outbox, OutboxColumn};
+use std::sync::Arc;
+
+use crate::kafka_producer::KafkaProducer;
+
+const BATCH_SIZE: u64 = 100;
+
+/// Publishes queued order commands to Kafka in insertion order and marks them sent.
+/// A command is marked only after Kafka acknowledged it, so delivery is at least once.
+pub async fn run_relay(db: DatabaseConnection, producer: Arc<KafkaProducer>) {
+    loop {
+        match relay_batch(&db, &producer).await {
+            Ok(0) => {
+                // Nothing queued, continue
+                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Error relaying outbox: {}", e);
+                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+/// Sends the oldest unsent commands and returns how many were sent. The rows stay locked until
+/// they are marked, so relays of other server instances wait instead of sending them again or
+/// overtaking them.
+async fn relay_batch(db: &DatabaseConnection, producer: &KafkaProducer) -> anyhow::Result<usize> {
+    let txn = db.begin().await?;
+
+    let entries = Outbox::find()
+        .filter(OutboxColumn::SentAt.is_null())
+        .order_by(OutboxColumn::Id, sea_orm::Order::Asc)
+        .limit(BATCH_SIZE)
+        .lock_exclusive()
+        .all(&txn)
+        .await?;
+
+    let mut sent = Vec::new();
+    let mut send_error = None;
+    for entry in entries {
+        let command = OrderCommand::from_json(&entry.payload)?;
+        if let Err(e) = producer.send_command(command).await {
+            // Keep the order of the remaining commands, they are retried on the next batch
+            send_error = Some(e);
+            break;
+        }
+        sent.push(entry.id);
+    }
+
+    if !sent.is_empty() {
+        Outbox::update_many()
+            .col_expr(OutboxColumn::SentAt, sea_orm::sea_query::Expr::value(chrono::Utc::now()))
+            .filter(OutboxColumn::Id.is_in(sent.clone()))
+            .exec(&txn)
+            .await?;
+    }
+    txn.commit().await?;
+
+    match send_error {
+        Some(e) => Err(e),
+        None => Ok(sent.len()),
+    }
+}
@@ -0,0 +1,140 @@
+use chrono::{DateTime, Duration, Utc};
+use rust_decimal::Decimal;
+use sea_orm::DatabaseConnection;
+use shared::{kafka, MatcherEvent};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use crate::{db, kafka_consumer::KafkaConsumer};
+
+/// Pair -> fills of the last 24 hours.
+pub type TickerStats = Arc<RwLock<HashMap<String, RollingStats>>>;
+
+struct Fill {
+    trade_id: Uuid,
+    rate: Decimal,
+    amount: Decimal,
+    created_at: DateTime<Utc>,
+}
+
+/// Rolling 24 hour statistics of one pair, updated as fills arrive and expire. `highs` and
+/// `lows` keep only the fills that can still become the window's high or low, so both are
+/// read from their front.
+#[derive(Default)]
+pub struct RollingStats {
+    fills: VecDeque<Fill>,
+    trade_ids: HashSet<Uuid>,
+    highs: VecDeque<(DateTime<Utc>, Decimal)>,
+    lows: VecDeque<(DateTime<Utc>, Decimal)>,
+    volume: Decimal,
+    last: Option<Decimal>,
+}
+
+impl RollingStats {
+    /// Adds a fill unless it was already counted. Fills arrive in time order per pair.
+    fn record(&mut self, fill: Fill) {
+        if !self.trade_ids.insert(fill.trade_id) {
+            return;
+        }
+
+        while self.highs.back().is_some_and(|&(_, rate)| rate <= fill.rate) {
+            self.highs.pop_back();
+        }
+        self.highs.push_back((fill.created_at, fill.rate));
+        while self.lows.back().is_some_and(|&(_, rate)| rate >= fill.rate) {
+            self.lows.pop_back();
+        }
+        self.lows.push_back((fill.created_at, fill.rate));
+
+        self.volume += fill.amount;
+        self.last = Some(fill.rate);
+        self.fills.push_back(fill);
+    }
+
+    /// Drops the fills that left the 24 hour window.
+    fn expire(&mut self, now: DateTime<Utc>) {
+        let cutoff = now - Duration::hours(24);
+        while self.fills.front().is_some_and(|fill| fill.created_at < cutoff) {
+            if let Some(fill) = self.fills.pop_front() {
+                self.trade_ids.remove(&fill.trade_id);
+                self.volume -= fill.amount;
+            }
+        }
+        while self.highs.front().is_some_and(|&(at, _)| at < cutoff) {
+            self.highs.pop_front();
+        }
+        while self.lows.front().is_some_and(|&(at, _)| at < cutoff) {
+            self.lows.pop_front();
+        }
+    }
+
+    /// The last price ever seen, and the high, low and base currency volume of the last 24 hours.
+    pub fn summary(&mut self, now: DateTime<Utc>) -> (Option<Decimal>, Option<Decimal>, Option<Decimal>, Decimal) {
+        self.expire(now);
+        (
+            self.last,
+            self.highs.front().map(|&(_, rate)| rate),
+            self.lows.front().map(|&(_, rate)| rate),
+            self.volume,
+        )
+    }
+}
+
+async fn record(stats: &TickerStats, pair: &str, fill: Fill) {
+    // Settlement may have been behind by more than the window
+    if fill.created_at < Utc::now() - Duration::hours(24) {
+        return;
+    }
+    let mut stats = stats.write().await;
+    let pair_stats = stats.entry(pair.to_string()).or_default();
+    pair_stats.record(fill);
+    pair_stats.expire(Utc::now());
+}
+
+/// Backfills the last 24 hours from the `trades` table, then follows the fills the matcher
+/// publishes from the offsets settlement had committed before the backfill, so fills it had not
+/// applied yet are not missed. Fills seen both ways are counted once by trade id.
+pub async fn run_stats(db: DatabaseConnection, stats: TickerStats) -> anyhow::Result<()> {
+    // Reading the offsets blocks on the broker
+    let offsets = tokio::task::spawn_blocking(|| kafka::settlement_offsets("matched-orders")).await??;
+
+    let trades = db::get_trades_since(&db, Utc::now() - Duration::hours(24)).await?;
+    for trade in trades {
+        let fill = Fill {
+            trade_id: trade.id,
+            rate: trade.rate,
+            amount: trade.amount,
+            created_at: trade.created_at,
+        };
+        record(&stats, &trade.pair, fill).await;
+    }
+
+    let consumer = KafkaConsumer::from_offsets("matched-orders", &offsets)?;
+    loop {
+        match consumer.consume_message().await {
+            Ok(Some((_, payload))) => match MatcherEvent::from_json(&payload) {
+                Ok(MatcherEvent::Matched(matched)) => {
+                    let fill = Fill {
+                        trade_id: matched.trade_id,
+                        rate: matched.rate,
+                        amount: matched.amount,
+                        created_at: matched.created_at,
+                    };
+                    record(&stats, &matched.pair, fill).await;
+                }
+                Ok(MatcherEvent::Cancelled(_) | MatcherEvent::Decremented(_)) => {}
+                Err(e) => eprintln!("Error decoding matcher event: {}", e),
+            },
+            Ok(None) => {
+                // No message, continue
+                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+            }
+            Err(e) => {
+                eprintln!("Error consuming message: {}", e);
+                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,332 @@
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    http::{HeaderMap, Uri},
+    response::Response,
+};
+use serde::Deserialize;
+use serde_json::json;
+use shared::{CexError, MatchedOrder, MatcherEvent, OrderBookDelta, OrderEvent, PriceLevel, Side};
+use std::collections::HashSet;
+use tokio::sync::broadcast;
+
+use crate::{
+    auth::{self, Permission},
+    kafka_consumer::KafkaConsumer,
+    AppState,
+};
+
+const LOGIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// A message for every client subscribed to `channel`, already encoded.
+#[derive(Debug, Clone)]
+pub struct FeedMessage {
+    pub channel: String,
+    pub payload: String,
+}
+
+#[derive(Deserialize)]
+struct ClientMessage {
+    #[serde(rename = "type")]
+    kind: String,
+    channel: String,
+}
+
+/// Forwards matcher output to WebSocket clients as Coincheck public channel messages:
+/// `{pair}-orderbook` from `orderbook-deltas` and `{pair}-trades` from `matched-orders`.
+pub async fn run_market_feed(feed: broadcast::Sender<FeedMessage>) -> anyhow::Result<()> {
+    let consumer = KafkaConsumer::new(&["orderbook-deltas", "matched-orders"])?;
+
+    loop {
+        match consumer.consume_message().await {
+            Ok(Some((topic, payload))) => {
+                let message = match topic.as_str() {
+                    "orderbook-deltas" => OrderBookDelta::from_json(&payload).map(|d| Some(orderbook_message(&d))),
+                    _ => MatcherEvent::from_json(&payload).map(|event| match event {
+                        MatcherEvent::Matched(matched) => Some(trades_message(&matched)),
+                        MatcherEvent::Cancelled(_) | MatcherEvent::Decremented(_) => None,
+                    }),
+                };
+                match message {
+                    // Sending only fails when no client is connected
+                    Ok(Some(message)) => {
+                        let _ = feed.send(message);
+                    }
+                    Ok(None) => {}
+                    Err(e) => eprintln!("Error decoding {} message: {}", topic, e),
+                }
+            }
+            Ok(None) => {
+                // No message, continue
+                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+            }
+            Err(e) => {
+                eprintln!("Error consuming message: {}", e);
+                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+/// `["btc_jpy", {"bids": [["rate", "amount"]], "asks": [...], "last_update_at": "unix time"}]`,
+/// an amount of "0" removes the level.
+fn orderbook_message(delta: &OrderBookDelta) -> FeedMessage {
+    let levels = |levels: &[PriceLevel]| {
+        levels
+            .iter()
+            .map(|level| [level.rate.to_string(), level.amount.to_string()])
+            .collect::<Vec<_>>()
+    };
+    let payload = json!([
+        delta.pair,
+        {
+            "bids": levels(&delta.bids),
+            "asks": levels(&delta.asks),
+            "last_update_at": delta.created_at.timestamp().to_string(),
+        }
+    ]);
+
+    FeedMessage {
+        channel: format!("{}-orderbook", delta.pair),
+        payload: payload.to_string(),
+    }
+}
+
+/// `[["unix time", "trade id", "btc_jpy", "rate", "amount", "taker side", "taker order id", "maker order id"]]`
+fn trades_message(matched: &MatchedOrder) -> FeedMessage {
+    let (taker_order_id, maker_order_id) = match matched.taker_side {
+        Side::Buy => (matched.buy_order_id, matched.sell_order_id),
+        Side::Sell => (matched.sell_order_id, matched.buy_order_id),
+    };
+    let payload = json!([[
+        matched.created_at.timestamp().to_string(),
+        matched.trade_id.to_string(),
+        matched.pair,
+        matched.rate.to_string(),
+        matched.amount.to_string(),
+        matched.taker_side.as_str(),
+        taker_order_id.to_string(),
+        maker_order_id.to_string(),
+    ]]);
+
+    FeedMessage {
+        channel: format!("{}-trades", matched.pair),
+        payload: payload.to_string(),
+    }
+}
+
+pub async fn public_ws(ws: WebSocketUpgrade, State(state): State<AppState>) -> Response {
+    ws.on_upgrade(move |socket| serve_public(socket, state))
+}
+
+/// Clients send `{"type": "subscribe", "channel": "btc_jpy-trades"}` (or `unsubscribe`) and
+/// receive the messages of their channels from then on.
+async fn serve_public(mut socket: WebSocket, state: AppState) {
+    let mut feed = state.market_feed.subscribe();
+    let mut channels: HashSet<String> = HashSet::new();
+
+    loop {
+        tokio::select! {
+            incoming = socket.recv() => {
+                let text = match incoming {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                let Ok(request) = serde_json::from_str::<ClientMessage>(&text) else {
+                    continue;
+                };
+                if !is_public_channel(&state, &request.channel) {
+                    continue;
+                }
+                match request.kind.as_str() {
+                    "subscribe" => {
+                        channels.insert(request.channel);
+                    }
+                    "unsubscribe" => {
+                        channels.remove(&request.channel);
+                    }
+                    _ => {}
+                }
+            }
+            message = feed.recv() => {
+                match message {
+                    Ok(message) if channels.contains(&message.channel) => {
+                        if socket.send(Message::Text(message.payload)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    // The client fell behind, later deltas still apply to the levels they touch
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        }
+    }
+}
+
+fn is_public_channel(state: &AppState, channel: &str) -> bool {
+    let pair = channel
+        .strip_suffix("-orderbook")
+        .or_else(|| channel.strip_suffix("-trades"));
+    pair.is_some_and(|pair| state.pairs.get(pair).is_some())
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum PrivateClientMessage {
+    Login {
+        access_key: String,
+        access_nonce: String,
+        access_signature: String,
+    },
+    Subscribe {
+        channels: Vec<String>,
+    },
+    Unsubscribe {
+        channels: Vec<String>,
+    },
+}
+
+/// Forwards the order events settlement publishes after each committed change to the private
+/// WebSocket connections, which pick out their own user's events.
+pub async fn run_order_events(order_events: broadcast::Sender<OrderEvent>) -> anyhow::Result<()> {
+    let consumer = KafkaConsumer::new(&["order-events"])?;
+
+    loop {
+        match consumer.consume_message().await {
+            Ok(Some((_, payload))) => match OrderEvent::from_json(&payload) {
+                // Sending only fails when no client is connected
+                Ok(event) => {
+                    let _ = order_events.send(event);
+                }
+                Err(e) => eprintln!("Error decoding order event: {}", e),
+            },
+            Ok(None) => {
+                // No message, continue
+                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+            }
+            Err(e) => {
+                eprintln!("Error consuming message: {}", e);
+                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+pub async fn private_ws(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Response {
+    // The login is signed like a private API request with an empty body
+    let url = auth::request_url(&headers, &uri);
+    ws.on_upgrade(move |socket| serve_private(socket, state, url))
+}
+
+/// The first message must be a login signed with an API key that can read. Afterwards the
+/// client subscribes to `order-events` and `execution-events` and receives its own events.
+async fn serve_private(mut socket: WebSocket, state: AppState, url: String) {
+    let mut events = state.order_events.subscribe();
+
+    let login = match tokio::time::timeout(LOGIN_TIMEOUT, socket.recv()).await {
+        Ok(Some(Ok(Message::Text(text)))) => serde_json::from_str::<PrivateClientMessage>(&text).ok(),
+        _ => None,
+    };
+    let Some(PrivateClientMessage::Login { access_key, access_nonce, access_signature }) = login else {
+        let error = CexError::Unauthorized("Send a login message first".to_string());
+        let _ = socket.send(Message::Text(login_response(Err(&error)))).await;
+        return;
+    };
+    let user = match auth::authenticate_key(&state.db, &access_key, &access_nonce, &access_signature, &url, b"").await {
+        Ok(user) => user,
+        Err(e) => {
+            if e.is_internal() {
+                eprintln!("Private WebSocket login failed: {}", e);
+            }
+            let _ = socket.send(Message::Text(login_response(Err(&e)))).await;
+            return;
+        }
+    };
+    if let Err(e) = user.require(Permission::Read) {
+        let _ = socket.send(Message::Text(login_response(Err(&e)))).await;
+        return;
+    }
+    if socket.send(Message::Text(login_response(Ok(())))).await.is_err() {
+        return;
+    }
+
+    let mut channels: HashSet<String> = HashSet::new();
+    loop {
+        tokio::select! {
+            incoming = socket.recv() => {
+                let text = match incoming {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                match serde_json::from_str::<PrivateClientMessage>(&text) {
+                    Ok(PrivateClientMessage::Subscribe { channels: requested }) => {
+                        channels.extend(requested.into_iter().filter(|c| is_private_channel(c)));
+                    }
+                    Ok(PrivateClientMessage::Unsubscribe { channels: requested }) => {
+                        for channel in requested {
+                            channels.remove(&channel);
+                        }
+                    }
+                    Ok(PrivateClientMessage::Login { .. }) | Err(_) => {}
+                }
+            }
+            event = events.recv() => {
+                match event {
+                    Ok(event) if event.user_id() == user.user_id => {
+                        let Some((channel, payload)) = private_message(event) else {
+                            continue;
+                        };
+                        if channels.contains(channel) && socket.send(Message::Text(payload)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        eprintln!("Private WebSocket of {} skipped {} order events", user.user_id, skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        }
+    }
+}
+
+/// Login failures carry the same `error` code and `message` as REST error bodies.
+fn login_response(result: Result<(), &CexError>) -> String {
+    match result {
+        Ok(()) => json!({"type": "login", "success": true}),
+        Err(e) => json!({"type": "login", "success": false, "error": e.code(), "message": e.public_message()}),
+    }
+    .to_string()
+}
+
+fn is_private_channel(channel: &str) -> bool {
+    matches!(channel, "order-events" | "execution-events")
+}
+
+/// The channel of an order event and its message: the order or transaction as returned by the
+/// REST API, tagged with the channel name.
+fn private_message(event: OrderEvent) -> Option<(&'static str, String)> {
+    let (channel, mut payload) = match event {
+        OrderEvent::Order { order, .. } => ("order-events", serde_json::to_value(order).ok()?),
+        OrderEvent::Execution { client_order_id, transaction, .. } => {
+            let mut payload = serde_json::to_value(transaction).ok()?;
+            payload["client_order_id"] = json!(client_order_id);
+            ("execution-events", payload)
+        }
+    };
+    payload["channel"] = json!(channel);
+
+    Some((channel, payload.to_string()))
+}
@@ -1,21 +1,52 @@
-use sea_orm::{DatabaseConnection, EntityTrait, QueryFilter, ColumnTrait, TransactionTrait, ActiveModelTrait, Set};
-use shared::MatchedOrder;
+use sea_orm::{DatabaseConnection, DatabaseTransaction, EntityTrait, QueryFilter, ColumnTrait, TransactionTrait, ActiveModelTrait, Set, FromQueryResult, Statement, DatabaseBackend};
+use shared::{CancelledOrder, DecrementedOrder, MatchedOrder, OrderDecrement, OrderDecrementActiveModel, Order, OrderEvent, PairRegistry, Side, Trade, TradeActiveModel, Transaction};
 use shared::{Balance, BalanceActiveModel, BalanceColumn, OrderEntity, OrderActiveModel};
 use rust_decimal::Decimal;
 
+use crate::fees::{self, FeeSchedules};
+
+/// A balance whose `locked` differs from the funds held by the user's open orders.
+#[derive(Debug, FromQueryResult)]
+pub struct LockedMismatch {
+    pub user_id: String,
+    pub currency: String,
+    pub locked: Decimal,
+    pub expected: Decimal,
+}
+
 pub struct SettlementDB {
     db: DatabaseConnection,
+    pairs: PairRegistry,
+    fee_schedules: FeeSchedules,
+    // House account credited with trading fees
+    fee_account_id: String,
 }
 
 impl SettlementDB {
-    pub async fn new(database_url: &str) -> anyhow::Result<Self> {
+    pub async fn new(database_url: &str, fee_account_id: &str) -> anyhow::Result<Self> {
         let db = sea_orm::Database::connect(database_url).await?;
-        Ok(Self { db })
+        let pairs = PairRegistry::load(&db).await?;
+        let fee_schedules = FeeSchedules::load(&db).await?;
+        Ok(Self {
+            db,
+            pairs,
+            fee_schedules,
+            fee_account_id: fee_account_id.to_string(),
+        })
     }
 
-    pub async fn settle_order(&self, matched: MatchedOrder) -> anyhow::Result<()> {
+    /// Applies a fill to orders and balances and records it in `trades`, returning the events
+    /// to publish for both orders. Returns `None` without changing anything if the trade id was
+    /// already settled, so a redelivered event is applied once. The trade row is written in the
+    /// same transaction.
+    pub async fn settle_order(&self, matched: &MatchedOrder) -> anyhow::Result<Option<Vec<OrderEvent>>> {
         let txn = self.db.begin().await?;
 
+        if Trade::find_by_id(matched.trade_id).one(&txn).await?.is_some() {
+            txn.rollback().await?;
+            return Ok(None);
+        }
+
         // Get order details to find user_id
         let buy_order_model = OrderEntity::find_by_id(matched.buy_order_id)
             .one(&txn)
@@ -27,16 +58,52 @@ impl SettlementDB {
             .await?
             .ok_or_else(|| anyhow::anyhow!("Sell order not found"))?;
 
+        let pair = self
+            .pairs
+            .get(&matched.pair)
+            .ok_or_else(|| anyhow::anyhow!("Pair {} not found", matched.pair))?;
+        let base_currency = pair.base_currency.as_str();
+        let quote_currency = pair.quote_currency.as_str();
+
         let buy_user_id = buy_order_model.user_id.clone();
         let sell_user_id = sell_order_model.user_id.clone();
 
         let executed_at = chrono::Utc::now();
 
+        // The resting side is the maker. Each side pays its fee in the currency it receives:
+        // the buyer in the base currency, the seller in the quote currency.
+        let buy_is_maker = matched.taker_side == Side::Sell;
+        let buy_volume = fees::thirty_day_volume(&txn, &buy_user_id, &matched.pair).await?;
+        let sell_volume = fees::thirty_day_volume(&txn, &sell_user_id, &matched.pair).await?;
+        let buy_fee_rate = self.fee_schedules.rate(&matched.pair, buy_volume, buy_is_maker);
+        let sell_fee_rate = self.fee_schedules.rate(&matched.pair, sell_volume, !buy_is_maker);
+        let buy_fee = (matched.amount * buy_fee_rate).round_dp(8);
+        let sell_fee = (matched.amount * matched.rate * sell_fee_rate).round_dp(8);
+
         // Update buy order
+        // The buyer pays the matched (maker) rate but locked funds at its own limit rate,
+        // so release the limit-rate share and let the price improvement return to available
         let buy_total = matched.amount * matched.rate;
         let mut buy_order: OrderActiveModel = buy_order_model.into();
-        let new_remaining = buy_order.remaining_amount.as_ref() - matched.amount;
+        // Market buys track the quote amount left to spend instead of a base amount
+        let buy_filled = if buy_order.order_type.as_ref() == "market_buy" {
+            buy_total
+        } else {
+            matched.amount
+        };
+        let new_remaining = buy_order.remaining_amount.as_ref() - buy_filled;
+        let buy_locked = *buy_order.locked_amount.as_ref();
+        let buy_release = if new_remaining <= Decimal::ZERO {
+            buy_locked
+        } else {
+            match buy_order.rate.as_ref() {
+                Some(rate) => matched.amount * rate,
+                None => buy_total,
+            }
+            .min(buy_locked)
+        };
         buy_order.remaining_amount = Set(new_remaining);
+        buy_order.locked_amount = Set(buy_locked - buy_release);
         buy_order.status = Set(if new_remaining <= Decimal::ZERO {
             "filled".to_string()
         } else {
@@ -47,12 +114,19 @@ impl SettlementDB {
             buy_order.executed_at = Set(Some(executed_at));
         }
         buy_order.updated_at = Set(chrono::Utc::now());
-        buy_order.update(&txn).await?;
+        let buy_order = buy_order.update(&txn).await?;
 
         // Update sell order
         let mut sell_order: OrderActiveModel = sell_order_model.into();
         let new_remaining = sell_order.remaining_amount.as_ref() - matched.amount;
+        let sell_locked = *sell_order.locked_amount.as_ref();
+        let sell_release = if new_remaining <= Decimal::ZERO {
+            sell_locked
+        } else {
+            matched.amount.min(sell_locked)
+        };
         sell_order.remaining_amount = Set(new_remaining);
+        sell_order.locked_amount = Set(sell_locked - sell_release);
         sell_order.status = Set(if new_remaining <= Decimal::ZERO {
             "filled".to_string()
         } else {
@@ -63,87 +137,329 @@ impl SettlementDB {
             sell_order.executed_at = Set(Some(executed_at));
         }
         sell_order.updated_at = Set(chrono::Utc::now());
-        sell_order.update(&txn).await?;
+        let sell_order = sell_order.update(&txn).await?;
 
-        // Update balances for buy side (spend JPY, receive BTC)
-        // Unlock and deduct JPY
+        // Update balances for buy side (spend quote, receive base)
+        // Unlock and deduct quote currency
         let buy_balance = Balance::find()
             .filter(BalanceColumn::UserId.eq(buy_user_id.as_str()))
-            .filter(BalanceColumn::Currency.eq("JPY"))
+            .filter(BalanceColumn::Currency.eq(quote_currency))
             .one(&txn)
             .await?
             .ok_or_else(|| anyhow::anyhow!("Buy balance not found"))?;
 
         let mut buy_balance: BalanceActiveModel = buy_balance.into();
-        buy_balance.locked = Set(buy_balance.locked.as_ref() - buy_total);
+        buy_balance.locked = Set(buy_balance.locked.as_ref() - buy_release);
         buy_balance.balance = Set(buy_balance.balance.as_ref() - buy_total);
         buy_balance.update(&txn).await?;
 
-        // Add BTC
-        let buy_btc_balance = Balance::find()
-            .filter(BalanceColumn::UserId.eq(buy_user_id.as_str()))
-            .filter(BalanceColumn::Currency.eq("BTC"))
-            .one(&txn)
-            .await?;
-
-        match buy_btc_balance {
-            Some(btc_balance) => {
-                let mut btc_balance: BalanceActiveModel = btc_balance.into();
-                btc_balance.balance = Set(btc_balance.balance.as_ref() + matched.amount);
-                btc_balance.update(&txn).await?;
-            }
-            None => {
-                let new_btc_balance = BalanceActiveModel {
-                    user_id: Set(buy_user_id.clone()),
-                    currency: Set("BTC".to_string()),
-                    balance: Set(matched.amount),
-                    locked: Set(Decimal::ZERO),
-                };
-                new_btc_balance.insert(&txn).await?;
-            }
-        }
+        // Add base currency, less the buyer's fee
+        credit_balance(&txn, &buy_user_id, base_currency, matched.amount - buy_fee).await?;
 
-        // Update balances for sell side (spend BTC, receive JPY)
-        // Unlock and deduct BTC
+        // Update balances for sell side (spend base, receive quote)
+        // Unlock and deduct base currency
         let sell_balance = Balance::find()
             .filter(BalanceColumn::UserId.eq(sell_user_id.as_str()))
-            .filter(BalanceColumn::Currency.eq("BTC"))
+            .filter(BalanceColumn::Currency.eq(base_currency))
             .one(&txn)
             .await?
             .ok_or_else(|| anyhow::anyhow!("Sell balance not found"))?;
 
         let mut sell_balance: BalanceActiveModel = sell_balance.into();
-        sell_balance.locked = Set(sell_balance.locked.as_ref() - matched.amount);
+        sell_balance.locked = Set(sell_balance.locked.as_ref() - sell_release);
         sell_balance.balance = Set(sell_balance.balance.as_ref() - matched.amount);
         sell_balance.update(&txn).await?;
 
-        // Add JPY
+        // Add quote currency, less the seller's fee
         let sell_receive = matched.amount * matched.rate;
-        let sell_jpy_balance = Balance::find()
-            .filter(BalanceColumn::UserId.eq(sell_user_id.as_str()))
-            .filter(BalanceColumn::Currency.eq("JPY"))
+        credit_balance(&txn, &sell_user_id, quote_currency, sell_receive - sell_fee).await?;
+
+        // Collect fees in the house account
+        if !buy_fee.is_zero() {
+            credit_balance(&txn, &self.fee_account_id, base_currency, buy_fee).await?;
+        }
+        if !sell_fee.is_zero() {
+            credit_balance(&txn, &self.fee_account_id, quote_currency, sell_fee).await?;
+        }
+
+        // Record the fill itself
+        let (maker_order_id, taker_order_id, maker_user_id, taker_user_id, maker_fee, taker_fee) =
+            match matched.taker_side {
+                Side::Buy => (
+                    matched.sell_order_id,
+                    matched.buy_order_id,
+                    sell_user_id,
+                    buy_user_id,
+                    sell_fee,
+                    buy_fee,
+                ),
+                Side::Sell => (
+                    matched.buy_order_id,
+                    matched.sell_order_id,
+                    buy_user_id,
+                    sell_user_id,
+                    buy_fee,
+                    sell_fee,
+                ),
+            };
+        let trade = TradeActiveModel {
+            id: Set(matched.trade_id),
+            pair: Set(matched.pair.clone()),
+            rate: Set(matched.rate),
+            amount: Set(matched.amount),
+            taker_side: Set(matched.taker_side.as_str().to_string()),
+            maker_order_id: Set(maker_order_id),
+            taker_order_id: Set(taker_order_id),
+            maker_user_id: Set(maker_user_id),
+            taker_user_id: Set(taker_user_id),
+            maker_fee: Set(maker_fee),
+            taker_fee: Set(taker_fee),
+            created_at: Set(matched.created_at),
+        };
+        let trade = trade.insert(&txn).await?;
+
+        txn.commit().await?;
+
+        let (taker_order, maker_order) = match matched.taker_side {
+            Side::Buy => (&buy_order, &sell_order),
+            Side::Sell => (&sell_order, &buy_order),
+        };
+        let mut events = vec![
+            OrderEvent::Execution {
+                user_id: taker_order.user_id.clone(),
+                client_order_id: taker_order.client_order_id.clone(),
+                transaction: Transaction::from_trade(&trade, pair, true),
+            },
+            OrderEvent::Execution {
+                user_id: maker_order.user_id.clone(),
+                client_order_id: maker_order.client_order_id.clone(),
+                transaction: Transaction::from_trade(&trade, pair, false),
+            },
+        ];
+        for order in [buy_order, sell_order] {
+            events.push(OrderEvent::Order {
+                user_id: order.user_id.clone(),
+                order: Order::from(order),
+            });
+        }
+
+        Ok(Some(events))
+    }
+
+    /// Marks an order cancelled and releases the funds still locked for its remainder.
+    /// Also closes market orders whose remainder found no liquidity.
+    /// Returns the event to publish, or `None` if the order was already closed.
+    pub async fn cancel_order(&self, cancelled: &CancelledOrder) -> anyhow::Result<Option<OrderEvent>> {
+        let txn = self.db.begin().await?;
+
+        let order_model = OrderEntity::find_by_id(cancelled.order_id)
             .one(&txn)
-            .await?;
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Order not found"))?;
 
-        match sell_jpy_balance {
-            Some(jpy_balance) => {
-                let mut jpy_balance: BalanceActiveModel = jpy_balance.into();
-                jpy_balance.balance = Set(jpy_balance.balance.as_ref() + sell_receive);
-                jpy_balance.update(&txn).await?;
-            }
-            None => {
-                let new_jpy_balance = BalanceActiveModel {
-                    user_id: Set(sell_user_id.clone()),
-                    currency: Set("JPY".to_string()),
-                    balance: Set(sell_receive),
-                    locked: Set(Decimal::ZERO),
-                };
-                new_jpy_balance.insert(&txn).await?;
+        if order_model.status == "filled" || order_model.status == "cancelled" {
+            txn.rollback().await?;
+            return Ok(None);
+        }
+
+        if order_model.remaining_amount != cancelled.remaining_amount {
+            eprintln!(
+                "Remaining amount mismatch for order {}: db={}, matcher={}",
+                order_model.id, order_model.remaining_amount, cancelled.remaining_amount
+            );
+        }
+
+        let user_id = order_model.user_id.clone();
+
+        // Buy orders lock the quote currency, sell orders the base currency
+        let pair = self
+            .pairs
+            .get(&order_model.pair)
+            .ok_or_else(|| anyhow::anyhow!("Pair {} not found", order_model.pair))?;
+        let is_buy = matches!(order_model.order_type.as_str(), "buy" | "market_buy");
+        let currency = pair.locked_currency(is_buy).to_string();
+        let unlock_amount = order_model.locked_amount;
+
+        let mut order: OrderActiveModel = order_model.into();
+        order.locked_amount = Set(Decimal::ZERO);
+        order.status = Set("cancelled".to_string());
+        order.updated_at = Set(chrono::Utc::now());
+        let order = order.update(&txn).await?;
+
+        let balance = Balance::find()
+            .filter(BalanceColumn::UserId.eq(user_id.as_str()))
+            .filter(BalanceColumn::Currency.eq(currency.as_str()))
+            .one(&txn)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Balance not found"))?;
+
+        let mut balance: BalanceActiveModel = balance.into();
+        balance.locked = Set(balance.locked.as_ref() - unlock_amount);
+        balance.update(&txn).await?;
+
+        txn.commit().await?;
+
+        Ok(Some(OrderEvent::Order {
+            user_id,
+            order: Order::from(order),
+        }))
+    }
+
+    /// Takes the part of an open order removed by self-trade prevention off its amount and
+    /// releases the funds locked for it. Returns `None` without changing anything if the
+    /// decrement was already applied or the order is closed. The decrement is recorded in
+    /// `order_decrements` in the same transaction.
+    pub async fn decrement_order(&self, decremented: &DecrementedOrder) -> anyhow::Result<Option<OrderEvent>> {
+        let txn = self.db.begin().await?;
+
+        if OrderDecrement::find_by_id(decremented.id).one(&txn).await?.is_some() {
+            txn.rollback().await?;
+            return Ok(None);
+        }
+
+        let order_model = OrderEntity::find_by_id(decremented.order_id)
+            .one(&txn)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Order not found"))?;
+
+        if order_model.status == "filled" || order_model.status == "cancelled" {
+            txn.rollback().await?;
+            return Ok(None);
+        }
+
+        let new_remaining = order_model.remaining_amount - decremented.amount;
+        if new_remaining != decremented.remaining_amount {
+            eprintln!(
+                "Remaining amount mismatch for order {}: db={}, matcher={}",
+                order_model.id, new_remaining, decremented.remaining_amount
+            );
+        }
+
+        let user_id = order_model.user_id.clone();
+
+        // Limit buys locked the quote currency at their rate, every other order locked the
+        // amount itself
+        let pair = self
+            .pairs
+            .get(&order_model.pair)
+            .ok_or_else(|| anyhow::anyhow!("Pair {} not found", order_model.pair))?;
+        let is_buy = matches!(order_model.order_type.as_str(), "buy" | "market_buy");
+        let currency = pair.locked_currency(is_buy).to_string();
+        let unlock_amount = match order_model.rate {
+            Some(rate) if is_buy => decremented.amount * rate,
+            _ => decremented.amount,
+        }
+        .min(order_model.locked_amount);
+
+        let mut order: OrderActiveModel = order_model.into();
+        order.amount = Set(order.amount.as_ref() - decremented.amount);
+        order.remaining_amount = Set(new_remaining);
+        order.locked_amount = Set(order.locked_amount.as_ref() - unlock_amount);
+        order.updated_at = Set(chrono::Utc::now());
+        let order = order.update(&txn).await?;
+
+        let balance = Balance::find()
+            .filter(BalanceColumn::UserId.eq(user_id.as_str()))
+            .filter(BalanceColumn::Currency.eq(currency.as_str()))
+            .one(&txn)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Balance not found"))?;
+
+        let mut balance: BalanceActiveModel = balance.into();
+        balance.locked = Set(balance.locked.as_ref() - unlock_amount);
+        balance.update(&txn).await?;
+
+        let record = OrderDecrementActiveModel {
+            id: Set(decremented.id),
+            order_id: Set(decremented.order_id),
+            amount: Set(decremented.amount),
+            created_at: Set(decremented.created_at),
+        };
+        record.insert(&txn).await?;
+
+        txn.commit().await?;
+
+        Ok(Some(OrderEvent::Order {
+            user_id,
+            order: Order::from(order),
+        }))
+    }
+
+    /// Finds balances whose `locked` does not equal the sum of `locked_amount` over the user's
+    /// open orders, and resets them to the expected value when `repair` is set.
+    pub async fn reconcile_locked_balances(&self, repair: bool) -> anyhow::Result<Vec<LockedMismatch>> {
+        let txn = self.db.begin().await?;
+
+        // Lock the balance rows so settlement cannot move funds while we compare
+        let stmt = Statement::from_string(
+            DatabaseBackend::Postgres,
+            r#"SELECT b.user_id, b.currency, b.locked, COALESCE(o.expected, 0) AS expected
+               FROM balances b
+               LEFT JOIN (
+                   SELECT o.user_id,
+                          CASE WHEN o.order_type IN ('buy', 'market_buy') THEN p.quote_currency ELSE p.base_currency END AS currency,
+                          SUM(o.locked_amount) AS expected
+                   FROM orders o
+                   JOIN pairs p ON p.pair = o.pair
+                   WHERE o.status IN ('pending', 'partially_filled')
+                   GROUP BY 1, 2
+               ) o ON o.user_id = b.user_id AND o.currency = b.currency
+               WHERE b.locked <> COALESCE(o.expected, 0)
+               FOR UPDATE OF b"#,
+        );
+
+        let mismatches = LockedMismatch::find_by_statement(stmt).all(&txn).await?;
+
+        if repair {
+            for mismatch in &mismatches {
+                let balance = Balance::find()
+                    .filter(BalanceColumn::UserId.eq(mismatch.user_id.as_str()))
+                    .filter(BalanceColumn::Currency.eq(mismatch.currency.as_str()))
+                    .one(&txn)
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("Balance not found"))?;
+
+                let mut balance: BalanceActiveModel = balance.into();
+                balance.locked = Set(mismatch.expected);
+                balance.update(&txn).await?;
             }
         }
 
         txn.commit().await?;
 
-        Ok(())
+        Ok(mismatches)
+    }
+}
+
+/// Adds `amount` to the user's balance in `currency`, creating the balance row if needed.
+async fn credit_balance(
+    txn: &DatabaseTransaction,
+    user_id: &str,
+    currency: &str,
+    amount: Decimal,
+) -> anyhow::Result<()> {
+    let balance = Balance::find()
+        .filter(BalanceColumn::UserId.eq(user_id))
+        .filter(BalanceColumn::Currency.eq(currency))
+        .one(txn)
+        .await?;
+
+    match balance {
+        Some(balance) => {
+            let mut balance: BalanceActiveModel = balance.into();
+            balance.balance = Set(balance.balance.as_ref() + amount);
+            balance.update(txn).await?;
+        }
+        None => {
+            let new_balance = BalanceActiveModel {
+                user_id: Set(user_id.to_string()),
+                currency: Set(currency.to_string()),
+                balance: Set(amount),
+                locked: Set(Decimal::ZERO),
+            };
+            new_balance.insert(txn).await?;
+        }
     }
+
+    Ok(())
 }
@@ -0,0 +1,53 @@
+use rust_decimal::Decimal;
+use sea_orm::{ConnectionTrait, DatabaseBackend, DatabaseConnection, EntityTrait, FromQueryResult, Statement};
+use shared::{FeeSchedule, FeeScheduleModel};
+use std::collections::HashMap;
+
+#[derive(FromQueryResult)]
+struct VolumeRow {
+    volume: Option<Decimal>,
+}
+
+/// Maker and taker fee rates per pair and 30-day volume tier, loaded from `fee_schedules`.
+pub struct FeeSchedules {
+    // Pair -> tiers sorted by descending volume threshold
+    tiers: HashMap<String, Vec<FeeScheduleModel>>,
+}
+
+impl FeeSchedules {
+    pub async fn load(db: &DatabaseConnection) -> anyhow::Result<Self> {
+        let mut tiers: HashMap<String, Vec<FeeScheduleModel>> = HashMap::new();
+        for schedule in FeeSchedule::find().all(db).await? {
+            tiers.entry(schedule.pair.clone()).or_default().push(schedule);
+        }
+        for pair_tiers in tiers.values_mut() {
+            pair_tiers.sort_by_key(|t| std::cmp::Reverse(t.min_volume_30d));
+        }
+
+        Ok(Self { tiers })
+    }
+
+    /// Returns the fee rate for a user with `volume_30d` traded in `pair`.
+    /// Pairs without a schedule are traded without fees.
+    pub fn rate(&self, pair: &str, volume_30d: Decimal, is_maker: bool) -> Decimal {
+        self.tiers
+            .get(pair)
+            .and_then(|pair_tiers| pair_tiers.iter().find(|t| volume_30d >= t.min_volume_30d))
+            .map(|t| if is_maker { t.maker_fee_rate } else { t.taker_fee_rate })
+            .unwrap_or(Decimal::ZERO)
+    }
+}
+
+/// The user's traded volume in `pair` over the last 30 days, in the pair's quote currency.
+pub async fn thirty_day_volume<C: ConnectionTrait>(db: &C, user_id: &str, pair: &str) -> anyhow::Result<Decimal> {
+    let stmt = Statement::from_sql_and_values(
+        DatabaseBackend::Postgres,
+        r#"SELECT SUM(amount * rate) AS volume FROM trades
+           WHERE pair = $2 AND created_at > NOW() - INTERVAL '30 days'
+             AND (maker_user_id = $1 OR taker_user_id = $1)"#,
+        vec![user_id.into(), pair.into()],
+    );
+
+    let row = VolumeRow::find_by_statement(stmt).one(db).await?;
+    Ok(row.and_then(|r| r.volume).unwrap_or(Decimal::ZERO))
+}
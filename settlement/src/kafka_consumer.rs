@@ -1,11 +1,19 @@
 use rdkafka::{
     config::ClientConfig,
-    consumer::{stream_consumer::StreamConsumer, Consumer},
-    Message,
+    consumer::{stream_consumer::StreamConsumer, CommitMode, Consumer},
+    Message, Offset, TopicPartitionList,
 };
-use shared::MatchedOrder;
+use shared::MatcherEvent;
 use anyhow::Result;
 
+/// A decoded event together with its position in the topic, committed once settled.
+pub struct ConsumedEvent {
+    pub event: MatcherEvent,
+    topic: String,
+    partition: i32,
+    offset: i64,
+}
+
 pub struct KafkaConsumer {
     consumer: StreamConsumer,
 }
@@ -20,7 +28,8 @@ impl KafkaConsumer {
             .set("group.id", "settlement")
             .set("enable.partition.eof", "false")
             .set("session.timeout.ms", "6000")
-            .set("enable.auto.commit", "true")
+            // Offsets are committed only after the settlement transaction commits
+            .set("enable.auto.commit", "false")
             .set("auto.offset.reset", "earliest")
             .create()?;
 
@@ -29,12 +38,17 @@ impl KafkaConsumer {
         Ok(Self { consumer })
     }
 
-    pub async fn consume_message(&self) -> Result<Option<MatchedOrder>> {
+    pub async fn consume_message(&self) -> Result<Option<ConsumedEvent>> {
         match self.consumer.recv().await {
             Ok(message) => {
                 let payload = message.payload().ok_or_else(|| anyhow::anyhow!("Empty payload"))?;
-                let matched_order = MatchedOrder::from_json(std::str::from_utf8(payload)?)?;
-                Ok(Some(matched_order))
+                let event = MatcherEvent::from_json(std::str::from_utf8(payload)?)?;
+                Ok(Some(ConsumedEvent {
+                    event,
+                    topic: message.topic().to_string(),
+                    partition: message.partition(),
+                    offset: message.offset(),
+                }))
             }
             Err(_e) => {
                 // Non-fatal errors (like timeout) are expected, return None
@@ -43,5 +57,12 @@ impl KafkaConsumer {
             }
         }
     }
-}
 
+    /// Commits the offset following `consumed`, so it is not redelivered after a restart.
+    pub fn commit(&self, consumed: &ConsumedEvent) -> Result<()> {
+        let mut offsets = TopicPartitionList::new();
+        offsets.add_partition_offset(&consumed.topic, consumed.partition, Offset::Offset(consumed.offset + 1))?;
+        self.consumer.commit(&offsets, CommitMode::Sync)?;
+        Ok(())
+    }
+}
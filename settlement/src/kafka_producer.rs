@@ -0,0 +1,37 @@
+use rdkafka::{
+    config::ClientConfig,
+    producer::{FutureProducer, FutureRecord},
+};
+use shared::OrderEvent;
+use std::time::Duration;
+
+pub struct KafkaProducer {
+    producer: FutureProducer,
+}
+
+impl KafkaProducer {
+    pub async fn new() -> anyhow::Result<Self> {
+        let kafka_bootstrap_servers = std::env::var("KAFKA_BOOTSTRAP_SERVERS")
+            .map_err(|_| anyhow::anyhow!("KAFKA_BOOTSTRAP_SERVERS environment variable is required"))?;
+
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", &kafka_bootstrap_servers)
+            .set("message.timeout.ms", "5000")
+            .create()?;
+
+        Ok(Self { producer })
+    }
+
+    pub async fn send_order_event(&self, event: &OrderEvent) -> anyhow::Result<()> {
+        let json = event.to_json()?;
+        // Use user id as key so each user's events arrive in order
+        let record = FutureRecord::to("order-events")
+            .key(event.user_id())
+            .payload(&json);
+
+        match self.producer.send(record, Duration::from_secs(0)).await {
+            Ok(_) => Ok(()),
+            Err((e, _)) => Err(anyhow::anyhow!("Failed to send order event: {}", e)),
+        }
+    }
+}
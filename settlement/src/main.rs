@@ -1,9 +1,13 @@
 mod kafka_consumer;
 mod db;
+mod fees;
+mod kafka_producer;
 
 use anyhow::Result;
 use kafka_consumer::KafkaConsumer;
 use db::SettlementDB;
+use kafka_producer::KafkaProducer;
+use shared::{MatcherEvent, OrderEvent};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -16,30 +20,62 @@ async fn main() -> Result<()> {
     let database_url = std::env::var("DATABASE_URL")
         .map_err(|_| anyhow::anyhow!("DATABASE_URL environment variable is required"))?;
     
-    let db = SettlementDB::new(&database_url).await?;
+    let fee_account_id = std::env::var("FEE_ACCOUNT_ID")
+        .map_err(|_| anyhow::anyhow!("FEE_ACCOUNT_ID environment variable is required"))?;
+
+    let db = SettlementDB::new(&database_url, &fee_account_id).await?;
+
+    // `settlement reconcile [--repair]` checks balances.locked against open orders and exits
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("reconcile") {
+        let repair = args.iter().any(|arg| arg == "--repair");
+        let mismatches = db.reconcile_locked_balances(repair).await?;
+        for mismatch in &mismatches {
+            println!("Locked mismatch: user={}, currency={}, locked={}, expected={}",
+                mismatch.user_id,
+                mismatch.currency,
+                mismatch.locked,
+                mismatch.expected);
+        }
+        println!("Found {} mismatched balances{}", mismatches.len(), if repair { ", repaired" } else { "" });
+        return Ok(());
+    }
 
     // Initialize Kafka consumer
     let consumer = KafkaConsumer::new("matched-orders")?;
 
-    println!("Settlement layer ready, consuming matched orders...");
+    // Initialize Kafka producer for order events, consumed by the private WebSocket
+    let producer = KafkaProducer::new().await?;
+
+    println!("Settlement layer ready, consuming matcher events...");
 
-    // Consume matched orders and settle them
+    // Consume matcher events and settle them. An event is retried until it is applied and its
+    // offset committed afterwards, so a crash redelivers it instead of losing it.
     loop {
         match consumer.consume_message().await {
-            Ok(Some(matched_order)) => {
-                println!("Processing matched order: buy={}, sell={}, amount={}", 
-                    matched_order.buy_order_id, 
-                    matched_order.sell_order_id, 
-                    matched_order.amount);
-                
-                match db.settle_order(matched_order).await {
-                    Ok(_) => {
-                        println!("Successfully settled order");
+            Ok(Some(consumed)) => {
+                let mut retry_delay = tokio::time::Duration::from_millis(100);
+                let order_events = loop {
+                    match apply_event(&db, &consumed.event).await {
+                        Ok(order_events) => break order_events,
+                        Err(e) => {
+                            eprintln!("Error applying matcher event, retrying in {:?}: {}", retry_delay, e);
+                            tokio::time::sleep(retry_delay).await;
+                            retry_delay = (retry_delay * 2).min(tokio::time::Duration::from_secs(30));
+                        }
                     }
-                    Err(e) => {
-                        eprintln!("Error settling order: {}", e);
+                };
+
+                // Notify owners only once the change is committed
+                for order_event in &order_events {
+                    if let Err(e) = producer.send_order_event(order_event).await {
+                        eprintln!("{}", e);
                     }
                 }
+
+                if let Err(e) = consumer.commit(&consumed) {
+                    eprintln!("Error committing offset: {}", e);
+                }
             }
             Ok(None) => {
                 // No message, continue
@@ -53,3 +89,45 @@ async fn main() -> Result<()> {
     }
 }
 
+/// Settles a matcher event and returns the order events it produced, none for a duplicate.
+async fn apply_event(db: &SettlementDB, event: &MatcherEvent) -> Result<Vec<OrderEvent>> {
+    match event {
+        MatcherEvent::Matched(matched_order) => {
+            println!("Processing matched order: trade={}, buy={}, sell={}, amount={}",
+                matched_order.trade_id,
+                matched_order.buy_order_id,
+                matched_order.sell_order_id,
+                matched_order.amount);
+
+            match db.settle_order(matched_order).await? {
+                Some(order_events) => {
+                    println!("Successfully settled order");
+                    Ok(order_events)
+                }
+                None => {
+                    println!("Trade {} already settled, skipping", matched_order.trade_id);
+                    Ok(Vec::new())
+                }
+            }
+        }
+        MatcherEvent::Cancelled(cancelled_order) => {
+            println!("Processing cancelled order: id={}, remaining={}",
+                cancelled_order.order_id,
+                cancelled_order.remaining_amount);
+
+            let order_event = db.cancel_order(cancelled_order).await?;
+            println!("Successfully cancelled order");
+            Ok(order_event.into_iter().collect())
+        }
+        MatcherEvent::Decremented(decremented_order) => {
+            println!("Processing decremented order: id={}, amount={}, remaining={}",
+                decremented_order.order_id,
+                decremented_order.amount,
+                decremented_order.remaining_amount);
+
+            let order_event = db.decrement_order(decremented_order).await?;
+            println!("Successfully decremented order");
+            Ok(order_event.into_iter().collect())
+        }
+    }
+}
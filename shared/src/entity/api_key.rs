@@ -0,0 +1,24 @@
+use sea_orm::entity::prelude::*;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "api_keys")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub access_key: String,
+    #[serde(skip_serializing)]
+    pub secret_key: String,
+    pub user_id: String,
+    pub can_trade: bool,
+    pub can_read: bool,
+    pub can_withdraw: bool,
+    /// Highest nonce accepted so far, every request must use a larger one
+    pub last_nonce: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,27 @@
+use sea_orm::entity::prelude::*;
+use rust_decimal::Decimal;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "candles")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub pair: String,
+    /// `1m`, `5m`, `1h` or `1d`
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub interval: String,
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub open_time: DateTime<Utc>,
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    /// Base currency amount traded
+    pub volume: Decimal,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
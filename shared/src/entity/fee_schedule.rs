@@ -0,0 +1,21 @@
+use sea_orm::entity::prelude::*;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "fee_schedules")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub pair: String,
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub tier: i32,
+    /// Minimum 30-day traded volume in the pair's quote currency to reach the tier
+    pub min_volume_30d: Decimal,
+    pub maker_fee_rate: Decimal,
+    pub taker_fee_rate: Decimal,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -1,6 +1,21 @@
+pub mod api_key;
 pub mod balance;
+pub mod candle;
+pub mod fee_schedule;
 pub mod order;
+pub mod order_decrement;
+pub mod outbox;
+pub mod pair;
+pub mod trade;
+pub mod user;
 
+pub use api_key::{Entity as ApiKey, Model as ApiKeyModel, ActiveModel as ApiKeyActiveModel, Column as ApiKeyColumn};
 pub use balance::{Entity as Balance, Model as BalanceModel, ActiveModel as BalanceActiveModel, Column as BalanceColumn};
+pub use candle::{Entity as Candle, Model as CandleModel, ActiveModel as CandleActiveModel, Column as CandleColumn};
+pub use fee_schedule::{Entity as FeeSchedule, Model as FeeScheduleModel, ActiveModel as FeeScheduleActiveModel, Column as FeeScheduleColumn};
 pub use order::{Entity as Order, Model as OrderModel, ActiveModel as OrderActiveModel, Column as OrderColumn};
-
+pub use order_decrement::{Entity as OrderDecrement, Model as OrderDecrementModel, ActiveModel as OrderDecrementActiveModel, Column as OrderDecrementColumn};
+pub use outbox::{Entity as Outbox, Model as OutboxModel, ActiveModel as OutboxActiveModel, Column as OutboxColumn};
+pub use pair::{Entity as Pair, Model as PairModel, ActiveModel as PairActiveModel, Column as PairColumn};
+pub use trade::{Entity as Trade, Model as TradeModel, ActiveModel as TradeActiveModel, Column as TradeColumn};
+pub use user::{Entity as User, Model as UserModel, ActiveModel as UserActiveModel, Column as UserColumn};
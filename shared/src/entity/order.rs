@@ -12,10 +12,17 @@ pub struct Model {
     pub user_id: String,
     pub pair: String,
     pub order_type: String,
-    pub rate: Decimal,
+    /// `None` for market orders
+    pub rate: Option<Decimal>,
+    /// Base currency amount, or the quote currency amount to spend for `market_buy`
     pub amount: Decimal,
     pub remaining_amount: Decimal,
+    /// Funds this order still holds in `balances.locked` (quote currency for buys, base currency for sells)
+    pub locked_amount: Decimal,
+    pub time_in_force: String,
     pub status: String,
+    /// Caller-chosen id, unique per user
+    pub client_order_id: Option<String>,
     pub executed_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
@@ -23,6 +30,18 @@ pub struct Model {
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::user::Entity",
+        from = "Column::UserId",
+        to = "super::user::Column::Id"
+    )]
+    User,
+}
+
+impl Related<super::user::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::User.def()
+    }
 }
 
 impl ActiveModelBehavior for ActiveModel {}
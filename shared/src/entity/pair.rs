@@ -0,0 +1,208 @@
+use sea_orm::entity::prelude::*;
+use rust_decimal::Decimal;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{CexError, OrderType};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "pairs")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub pair: String,
+    pub base_currency: String,
+    pub quote_currency: String,
+    /// Rates must be multiples of it
+    pub tick_size: Decimal,
+    /// Base currency amounts must be multiples of it
+    pub lot_size: Decimal,
+    /// Smallest order total in the quote currency
+    pub min_notional: Decimal,
+    /// Smallest order amount in the base currency
+    pub min_amount: Decimal,
+    pub status: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl Model {
+    pub fn is_active(&self) -> bool {
+        self.status == "active"
+    }
+
+    /// Currency an order of this side locks: the quote currency for buys, the base currency for sells.
+    pub fn locked_currency(&self, is_buy: bool) -> &str {
+        if is_buy {
+            &self.quote_currency
+        } else {
+            &self.base_currency
+        }
+    }
+
+    /// Whether `rate` is a positive multiple of the tick size.
+    pub fn is_valid_rate(&self, rate: Decimal) -> bool {
+        rate > Decimal::ZERO && is_multiple(rate, self.tick_size)
+    }
+
+    /// Whether `amount` is a positive multiple of the lot size.
+    pub fn is_valid_amount(&self, amount: Decimal) -> bool {
+        amount > Decimal::ZERO && is_multiple(amount, self.lot_size)
+    }
+
+    /// Rounds a base currency amount down to a multiple of the lot size.
+    pub fn round_to_lot(&self, amount: Decimal) -> Decimal {
+        if self.lot_size.is_zero() {
+            return amount;
+        }
+        (amount / self.lot_size).floor() * self.lot_size
+    }
+
+    /// Checks a new order against the pair's tick size, lot size, minimum amount and minimum
+    /// notional. `amount` is the quote currency amount to spend for `market_buy` orders, which
+    /// have no rate.
+    pub fn validate_order(&self, order_type: &OrderType, rate: Option<Decimal>, amount: Decimal) -> Result<(), CexError> {
+        if *order_type == OrderType::MarketBuy {
+            if amount.normalize().scale() > 8 {
+                return Err(CexError::InvalidOrder(format!(
+                    "market_buy_amount {} has more than 8 decimal places",
+                    amount
+                )));
+            }
+            if amount < self.min_notional {
+                return Err(CexError::InvalidOrder(format!(
+                    "market_buy_amount {} is below the minimum notional {} {} for {}",
+                    amount, self.min_notional, self.quote_currency, self.pair
+                )));
+            }
+            return Ok(());
+        }
+
+        if let Some(rate) = rate {
+            if !self.is_valid_rate(rate) {
+                return Err(CexError::InvalidOrder(format!(
+                    "Rate {} is not a multiple of the tick size {} for {}",
+                    rate, self.tick_size, self.pair
+                )));
+            }
+        }
+        if !self.is_valid_amount(amount) {
+            return Err(CexError::InvalidOrder(format!(
+                "Amount {} is not a multiple of the lot size {} for {}",
+                amount, self.lot_size, self.pair
+            )));
+        }
+        if amount < self.min_amount {
+            return Err(CexError::InvalidOrder(format!(
+                "Amount {} is below the minimum amount {} {} for {}",
+                amount, self.min_amount, self.base_currency, self.pair
+            )));
+        }
+        // Market sells have no rate to value them at before matching
+        if let Some(rate) = rate {
+            let total = rate * amount;
+            if total < self.min_notional {
+                return Err(CexError::InvalidOrder(format!(
+                    "Order total {} is below the minimum notional {} {} for {}",
+                    total, self.min_notional, self.quote_currency, self.pair
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether `value` is a whole multiple of `step`. A zero step allows any value.
+fn is_multiple(value: Decimal, step: Decimal) -> bool {
+    step.is_zero() || (value % step).is_zero()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn d(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    fn btc_jpy() -> Model {
+        Model {
+            pair: "btc_jpy".to_string(),
+            base_currency: "BTC".to_string(),
+            quote_currency: "JPY".to_string(),
+            tick_size: d("1"),
+            lot_size: d("0.00000001"),
+            min_notional: d("500"),
+            min_amount: d("0.001"),
+            status: "active".to_string(),
+            created_at: Utc::now(),
+        }
+    }
+
+    fn rejection(order_type: OrderType, rate: Option<&str>, amount: &str) -> String {
+        btc_jpy()
+            .validate_order(&order_type, rate.map(d), d(amount))
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn accepts_orders_on_the_pair_sizes() {
+        let pair = btc_jpy();
+        assert!(pair.validate_order(&OrderType::Buy, Some(d("5000000")), d("0.001")).is_ok());
+        assert!(pair.validate_order(&OrderType::MarketSell, None, d("0.001")).is_ok());
+        assert!(pair.validate_order(&OrderType::MarketBuy, None, d("500")).is_ok());
+    }
+
+    #[test]
+    fn rejects_rate_off_the_tick_size() {
+        assert_eq!(
+            rejection(OrderType::Buy, Some("5000000.5"), "0.01"),
+            "Invalid order: Rate 5000000.5 is not a multiple of the tick size 1 for btc_jpy"
+        );
+    }
+
+    #[test]
+    fn rejects_amount_off_the_lot_size() {
+        assert_eq!(
+            rejection(OrderType::Sell, Some("5000000"), "0.010000001"),
+            "Invalid order: Amount 0.010000001 is not a multiple of the lot size 0.00000001 for btc_jpy"
+        );
+    }
+
+    #[test]
+    fn rejects_amount_below_the_minimum_amount() {
+        assert_eq!(
+            rejection(OrderType::MarketSell, None, "0.0009"),
+            "Invalid order: Amount 0.0009 is below the minimum amount 0.001 BTC for btc_jpy"
+        );
+    }
+
+    #[test]
+    fn rejects_total_below_the_minimum_notional() {
+        assert_eq!(
+            rejection(OrderType::Buy, Some("100000"), "0.001"),
+            "Invalid order: Order total 100.000 is below the minimum notional 500 JPY for btc_jpy"
+        );
+    }
+
+    #[test]
+    fn rejects_market_buy_below_the_minimum_notional() {
+        assert_eq!(
+            rejection(OrderType::MarketBuy, None, "499"),
+            "Invalid order: market_buy_amount 499 is below the minimum notional 500 JPY for btc_jpy"
+        );
+    }
+
+    #[test]
+    fn rejects_market_buy_with_more_than_8_decimal_places() {
+        assert_eq!(
+            rejection(OrderType::MarketBuy, None, "1000.000000001"),
+            "Invalid order: market_buy_amount 1000.000000001 has more than 8 decimal places"
+        );
+    }
+}
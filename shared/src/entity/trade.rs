@@ -0,0 +1,31 @@
+use sea_orm::entity::prelude::*;
+use rust_decimal::Decimal;
+use uuid::Uuid;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "trades")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: Uuid,
+    pub pair: String,
+    pub rate: Decimal,
+    pub amount: Decimal,
+    /// Side of the incoming order that took liquidity, `buy` or `sell`
+    pub taker_side: String,
+    pub maker_order_id: Uuid,
+    pub taker_order_id: Uuid,
+    pub maker_user_id: String,
+    pub taker_user_id: String,
+    /// Charged in the currency the maker receives
+    pub maker_fee: Decimal,
+    /// Charged in the currency the taker receives
+    pub taker_fee: Decimal,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -8,6 +8,33 @@ pub enum CexError {
     #[error("Invalid order: {0}")]
     InvalidOrder(String),
 
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("Order not found")]
+    OrderNotFound,
+
+    #[error("Order is already {0}")]
+    OrderNotOpen(String),
+
+    #[error("Pair {0} is not supported")]
+    PairNotSupported(String),
+
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
+    #[error("Request body too large")]
+    PayloadTooLarge,
+
+    #[error("Service unavailable: {0}")]
+    Unavailable(String),
+
     #[error("Database error: {0}")]
     Database(String),
 
@@ -21,3 +48,91 @@ pub enum CexError {
     Internal(String),
 }
 
+impl CexError {
+    /// Stable machine-readable code returned as `error` in API error bodies.
+    pub fn code(&self) -> &'static str {
+        match self {
+            CexError::InsufficientBalance { .. } => "insufficient_balance",
+            CexError::InvalidOrder(_) => "invalid_order",
+            CexError::InvalidRequest(_) => "invalid_request",
+            CexError::OrderNotFound => "order_not_found",
+            CexError::OrderNotOpen(_) => "order_not_open",
+            CexError::PairNotSupported(_) => "pair_not_supported",
+            CexError::Unauthorized(_) => "unauthorized",
+            CexError::Forbidden(_) => "forbidden",
+            CexError::Conflict(_) => "conflict",
+            CexError::PayloadTooLarge => "payload_too_large",
+            CexError::Unavailable(_) => "unavailable",
+            CexError::Database(_) => "database_error",
+            CexError::Kafka(_) => "kafka_error",
+            CexError::Serialization(_) => "serialization_error",
+            CexError::Internal(_) => "internal_error",
+        }
+    }
+
+    /// Whether the error is a server-side failure rather than a problem with the request.
+    pub fn is_internal(&self) -> bool {
+        matches!(
+            self,
+            CexError::Database(_) | CexError::Kafka(_) | CexError::Serialization(_) | CexError::Internal(_)
+        )
+    }
+
+    /// Description safe to send to clients. Internal failures carry database or broker details,
+    /// those stay in the server log.
+    pub fn public_message(&self) -> String {
+        if self.is_internal() {
+            "Internal server error".to_string()
+        } else {
+            self.to_string()
+        }
+    }
+}
+
+impl From<sea_orm::DbErr> for CexError {
+    fn from(e: sea_orm::DbErr) -> Self {
+        CexError::Database(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for CexError {
+    fn from(e: serde_json::Error) -> Self {
+        CexError::Serialization(e.to_string())
+    }
+}
+
+/// Error responses carry `{"success": false, "error": <code>, "message": <description>}`.
+#[cfg(feature = "axum")]
+impl axum::response::IntoResponse for CexError {
+    fn into_response(self) -> axum::response::Response {
+        use axum::http::StatusCode;
+
+        let status = match &self {
+            CexError::InsufficientBalance { .. }
+            | CexError::InvalidOrder(_)
+            | CexError::InvalidRequest(_)
+            | CexError::OrderNotOpen(_)
+            | CexError::PairNotSupported(_) => StatusCode::BAD_REQUEST,
+            CexError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            CexError::Forbidden(_) => StatusCode::FORBIDDEN,
+            CexError::OrderNotFound => StatusCode::NOT_FOUND,
+            CexError::Conflict(_) => StatusCode::CONFLICT,
+            CexError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            CexError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            CexError::Database(_)
+            | CexError::Kafka(_)
+            | CexError::Serialization(_)
+            | CexError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        if self.is_internal() {
+            eprintln!("Request failed: {}", self);
+        }
+        let body = serde_json::json!({
+            "success": false,
+            "error": self.code(),
+            "message": self.public_message(),
+        });
+        (status, axum::Json(body)).into_response()
+    }
+}
@@ -0,0 +1,112 @@
+use anyhow::Result;
+use rdkafka::{
+    config::ClientConfig,
+    consumer::{BaseConsumer, Consumer},
+    Offset, TopicPartitionList,
+};
+use std::collections::HashMap;
+use std::time::Duration;
+
+pub const METADATA_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Partition ids of `topic`, empty if the topic does not exist yet. Consumers never create
+/// topics, `matched-orders` for example only appears once the matcher first publishes to it.
+pub fn partitions<C: Consumer>(consumer: &C, topic: &str) -> Result<Vec<i32>> {
+    let metadata = consumer.fetch_metadata(Some(topic), METADATA_TIMEOUT)?;
+    Ok(metadata
+        .topics()
+        .iter()
+        .filter(|t| t.name() == topic)
+        .flat_map(|t| t.partitions().iter().map(|p| p.id()))
+        .collect())
+}
+
+/// Waits until `topic` exists. Only for consumers that have nothing to do before it does.
+pub async fn wait_for_topic(topic: &str) -> Result<()> {
+    let consumer = settlement_group_consumer()?;
+    while partitions(&consumer, topic)?.is_empty() {
+        println!("Waiting for topic {} to be created...", topic);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    Ok(())
+}
+
+/// The next offset of every partition of `topic`, empty if the topic does not exist yet.
+pub fn high_watermarks(topic: &str) -> Result<HashMap<i32, i64>> {
+    let consumer = settlement_group_consumer()?;
+    partitions(&consumer, topic)?
+        .into_iter()
+        .map(|partition| {
+            let (_, high) = consumer.fetch_watermarks(topic, partition, METADATA_TIMEOUT)?;
+            Ok((partition, high))
+        })
+        .collect()
+}
+
+/// The offsets the `settlement` consumer group has committed on every partition of `topic`,
+/// empty if the topic does not exist yet. The database holds the effects of every event before
+/// them.
+pub fn settlement_offsets(topic: &str) -> Result<HashMap<i32, i64>> {
+    let consumer = settlement_group_consumer()?;
+    let partitions = partitions(&consumer, topic)?;
+    committed_offsets(&consumer, topic, &partitions)
+}
+
+/// Blocks until the `settlement` consumer group has committed `topic` up to `offsets`, so the
+/// database holds the effects of every event before them.
+pub async fn wait_for_settlement(topic: &str, offsets: &HashMap<i32, i64>) -> Result<()> {
+    if offsets.is_empty() {
+        return Ok(());
+    }
+    let consumer = settlement_group_consumer()?;
+    let partitions: Vec<i32> = offsets.keys().copied().collect();
+
+    loop {
+        let committed = committed_offsets(&consumer, topic, &partitions)?;
+        let lag: i64 = committed
+            .iter()
+            .map(|(partition, position)| (offsets[partition] - position).max(0))
+            .sum();
+        if lag == 0 {
+            return Ok(());
+        }
+        println!("Waiting for settlement to catch up on {}: {} events behind", topic, lag);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+fn committed_offsets(consumer: &BaseConsumer, topic: &str, partitions: &[i32]) -> Result<HashMap<i32, i64>> {
+    if partitions.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let mut list = TopicPartitionList::new();
+    for &partition in partitions {
+        list.add_partition(topic, partition);
+    }
+    let committed = consumer.committed_offsets(list, METADATA_TIMEOUT)?;
+
+    Ok(committed
+        .elements()
+        .iter()
+        .map(|element| {
+            let position = match element.offset() {
+                Offset::Offset(offset) => offset,
+                // The group has not committed on this partition yet
+                _ => 0,
+            };
+            (element.partition(), position)
+        })
+        .collect())
+}
+
+/// Only used to read metadata and the settlement group's offsets, it never joins the group.
+fn settlement_group_consumer() -> Result<BaseConsumer> {
+    let kafka_bootstrap_servers = std::env::var("KAFKA_BOOTSTRAP_SERVERS")
+        .map_err(|_| anyhow::anyhow!("KAFKA_BOOTSTRAP_SERVERS environment variable is required"))?;
+
+    let consumer: BaseConsumer = ClientConfig::new()
+        .set("bootstrap.servers", &kafka_bootstrap_servers)
+        .set("group.id", "settlement")
+        .create()?;
+    Ok(consumer)
+}
@@ -1,10 +1,21 @@
 pub mod models;
 pub mod error;
 pub mod entity;
+pub mod pair_registry;
+#[cfg(feature = "kafka")]
+pub mod kafka;
 
 pub use models::*;
 pub use error::*;
+pub use pair_registry::PairRegistry;
 // Entity types are exported with explicit names to avoid conflicts
 pub use entity::{Balance, BalanceModel, BalanceActiveModel, BalanceColumn};
 pub use entity::{Order as OrderEntity, OrderModel, OrderActiveModel, OrderColumn};
-
+pub use entity::{Pair, PairModel, PairActiveModel, PairColumn};
+pub use entity::{ApiKey, ApiKeyModel, ApiKeyActiveModel, ApiKeyColumn};
+pub use entity::{User, UserModel, UserActiveModel, UserColumn};
+pub use entity::{Trade, TradeModel, TradeActiveModel, TradeColumn};
+pub use entity::{FeeSchedule, FeeScheduleModel, FeeScheduleActiveModel, FeeScheduleColumn};
+pub use entity::{Outbox, OutboxModel, OutboxActiveModel, OutboxColumn};
+pub use entity::{Candle as CandleEntity, CandleModel, CandleActiveModel, CandleColumn};
+pub use entity::{OrderDecrement, OrderDecrementModel, OrderDecrementActiveModel, OrderDecrementColumn};
@@ -4,12 +4,191 @@ use chrono::{DateTime, Utc};
 use uuid::Uuid;
 use rust_decimal::Decimal;
 
+use crate::entity::{OrderModel, PairModel, TradeModel};
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum OrderType {
     #[serde(rename = "buy")]
     Buy,
     #[serde(rename = "sell")]
     Sell,
+    #[serde(rename = "market_buy")]
+    MarketBuy,
+    #[serde(rename = "market_sell")]
+    MarketSell,
+}
+
+impl OrderType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderType::Buy => "buy",
+            OrderType::Sell => "sell",
+            OrderType::MarketBuy => "market_buy",
+            OrderType::MarketSell => "market_sell",
+        }
+    }
+
+    pub fn is_buy(&self) -> bool {
+        matches!(self, OrderType::Buy | OrderType::MarketBuy)
+    }
+
+    pub fn is_market(&self) -> bool {
+        matches!(self, OrderType::MarketBuy | OrderType::MarketSell)
+    }
+
+    pub fn side(&self) -> Side {
+        if self.is_buy() {
+            Side::Buy
+        } else {
+            Side::Sell
+        }
+    }
+}
+
+impl std::str::FromStr for OrderType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "buy" => Ok(OrderType::Buy),
+            "sell" => Ok(OrderType::Sell),
+            "market_buy" => Ok(OrderType::MarketBuy),
+            "market_sell" => Ok(OrderType::MarketSell),
+            _ => Err(format!("Unknown order type: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Side {
+    #[serde(rename = "buy")]
+    Buy,
+    #[serde(rename = "sell")]
+    Sell,
+}
+
+impl Side {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Side::Buy => "buy",
+            Side::Sell => "sell",
+        }
+    }
+}
+
+impl std::str::FromStr for Side {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "buy" => Ok(Side::Buy),
+            "sell" => Ok(Side::Sell),
+            _ => Err(format!("Unknown side: {}", s)),
+        }
+    }
+}
+
+/// How long an order's unfilled remainder stays in the book.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TimeInForce {
+    /// Rest the remainder until it is filled or cancelled
+    #[default]
+    #[serde(rename = "good_til_cancelled")]
+    GoodTilCancelled,
+    /// Cancel whatever cannot be filled immediately
+    #[serde(rename = "immediate_or_cancel")]
+    ImmediateOrCancel,
+    /// Fill the whole amount immediately or cancel the order without trading
+    #[serde(rename = "fill_or_kill")]
+    FillOrKill,
+    /// Only add liquidity; cancel the order if it would match on arrival
+    #[serde(rename = "post_only")]
+    PostOnly,
+}
+
+impl TimeInForce {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimeInForce::GoodTilCancelled => "good_til_cancelled",
+            TimeInForce::ImmediateOrCancel => "immediate_or_cancel",
+            TimeInForce::FillOrKill => "fill_or_kill",
+            TimeInForce::PostOnly => "post_only",
+        }
+    }
+}
+
+impl std::str::FromStr for TimeInForce {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "good_til_cancelled" => Ok(TimeInForce::GoodTilCancelled),
+            "immediate_or_cancel" => Ok(TimeInForce::ImmediateOrCancel),
+            "fill_or_kill" => Ok(TimeInForce::FillOrKill),
+            "post_only" => Ok(TimeInForce::PostOnly),
+            _ => Err(format!("Unknown time in force: {}", s)),
+        }
+    }
+}
+
+/// Length of a candle. Candles start at multiples of their length since the Unix epoch, UTC.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum CandleInterval {
+    #[serde(rename = "1m")]
+    OneMinute,
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "1h")]
+    OneHour,
+    #[serde(rename = "1d")]
+    OneDay,
+}
+
+impl CandleInterval {
+    pub const ALL: [CandleInterval; 4] = [
+        CandleInterval::OneMinute,
+        CandleInterval::FiveMinutes,
+        CandleInterval::OneHour,
+        CandleInterval::OneDay,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CandleInterval::OneMinute => "1m",
+            CandleInterval::FiveMinutes => "5m",
+            CandleInterval::OneHour => "1h",
+            CandleInterval::OneDay => "1d",
+        }
+    }
+
+    pub fn seconds(&self) -> i64 {
+        match self {
+            CandleInterval::OneMinute => 60,
+            CandleInterval::FiveMinutes => 5 * 60,
+            CandleInterval::OneHour => 60 * 60,
+            CandleInterval::OneDay => 24 * 60 * 60,
+        }
+    }
+
+    /// Start of the candle containing `at`.
+    pub fn open_time(&self, at: DateTime<Utc>) -> DateTime<Utc> {
+        let seconds = at.timestamp().div_euclid(self.seconds()) * self.seconds();
+        DateTime::from_timestamp(seconds, 0).expect("candle start is within the range of `at`")
+    }
+}
+
+impl std::str::FromStr for CandleInterval {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1m" => Ok(CandleInterval::OneMinute),
+            "5m" => Ok(CandleInterval::FiveMinutes),
+            "1h" => Ok(CandleInterval::OneHour),
+            "1d" => Ok(CandleInterval::OneDay),
+            _ => Err(format!("Unknown candle interval: {}", s)),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -24,12 +203,78 @@ pub enum OrderStatus {
     Cancelled,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum AccountStatus {
+    #[serde(rename = "active")]
+    Active,
+    /// Can cancel orders but not place new ones
+    #[serde(rename = "frozen")]
+    Frozen,
+    #[serde(rename = "closed")]
+    Closed,
+}
+
+impl AccountStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccountStatus::Active => "active",
+            AccountStatus::Frozen => "frozen",
+            AccountStatus::Closed => "closed",
+        }
+    }
+}
+
+impl std::str::FromStr for AccountStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "active" => Ok(AccountStatus::Active),
+            "frozen" => Ok(AccountStatus::Frozen),
+            "closed" => Ok(AccountStatus::Closed),
+            _ => Err(format!("Unknown account status: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateAccountRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateApiKeyRequest {
+    #[serde(default)]
+    pub can_trade: bool,
+    #[serde(default = "default_true")]
+    pub can_read: bool,
+    #[serde(default)]
+    pub can_withdraw: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateOrderRequest {
     pub pair: String,
     pub order_type: OrderType,
-    pub rate: Decimal,
-    pub amount: Decimal,
+    /// Limit price, required for `buy` and `sell`
+    #[serde(default)]
+    pub rate: Option<Decimal>,
+    /// Base currency amount, required for `buy`, `sell` and `market_sell`
+    #[serde(default)]
+    pub amount: Option<Decimal>,
+    /// Quote currency amount to spend, required for `market_buy`
+    #[serde(default)]
+    pub market_buy_amount: Option<Decimal>,
+    #[serde(default)]
+    pub time_in_force: TimeInForce,
+    /// Caller-chosen id, unique per user. Placing an order with an id already in use returns
+    /// the existing order instead of creating a new one.
+    #[serde(default)]
+    pub client_order_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -37,17 +282,27 @@ pub struct Order {
     pub id: Uuid,
     pub pair: String,
     pub order_type: OrderType,
-    pub rate: Decimal,
+    /// `None` for market orders
+    pub rate: Option<Decimal>,
+    /// Base currency amount, or the quote currency amount to spend for `market_buy`
     pub amount: Decimal,
     pub remaining_amount: Decimal,
+    pub time_in_force: TimeInForce,
     pub status: OrderStatus,
+    pub client_order_id: Option<String>,
     pub created_at: DateTime<Utc>,
 }
 
+/// A price level of the aggregated (L2) book with its order count, or a single resting order
+/// of the per-order (L3) book with its id.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OrderBookEntry {
     pub price: Decimal,
     pub amount: Decimal,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order_count: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order_id: Option<Uuid>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -55,20 +310,223 @@ pub struct OrderBook {
     pub pair: String,
     pub bids: Vec<OrderBookEntry>,
     pub asks: Vec<OrderBookEntry>,
+    /// When the matcher took the book snapshot this view is served from
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<OrderModel> for Order {
+    fn from(o: OrderModel) -> Self {
+        let order_type: OrderType = o
+            .order_type
+            .parse()
+            .expect("order_type is constrained by the orders table");
+        let time_in_force: TimeInForce = o
+            .time_in_force
+            .parse()
+            .expect("time_in_force is constrained by the orders table");
+        let status = match o.status.as_str() {
+            "pending" => OrderStatus::Pending,
+            "partially_filled" => OrderStatus::PartiallyFilled,
+            "filled" => OrderStatus::Filled,
+            "cancelled" => OrderStatus::Cancelled,
+            _ => OrderStatus::Pending,
+        };
+
+        Self {
+            id: o.id,
+            pair: o.pair,
+            order_type,
+            rate: o.rate,
+            amount: o.amount,
+            remaining_amount: o.remaining_amount,
+            time_in_force,
+            status,
+            client_order_id: o.client_order_id,
+            created_at: o.created_at,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MatchedOrder {
+    pub trade_id: Uuid,
     pub buy_order_id: Uuid,
     pub sell_order_id: Uuid,
+    /// Side of the incoming order; the other order was resting in the book
+    pub taker_side: Side,
+    pub pair: String,
+    pub rate: Decimal,
+    pub amount: Decimal,
+    #[serde(default)]
+    pub buy_client_order_id: Option<String>,
+    #[serde(default)]
+    pub sell_client_order_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Total resting amount at one price.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PriceLevel {
+    pub rate: Decimal,
+    pub amount: Decimal,
+}
+
+/// Price levels of a pair's book that changed, published by the matcher after each command.
+/// Each level carries its new total amount, zero when the level emptied.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderBookDelta {
+    pub pair: String,
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A resting order of a price level, in time priority.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BookOrder {
+    pub id: Uuid,
+    pub amount: Decimal,
+}
+
+/// A price level with its total amount, number of orders and the orders themselves.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BookLevel {
+    pub rate: Decimal,
+    pub amount: Decimal,
+    pub order_count: u32,
+    pub orders: Vec<BookOrder>,
+}
+
+/// The best price levels of a pair's book, best price first, published by the matcher for the
+/// API server to serve the order book from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderBookSnapshot {
+    pub pair: String,
+    pub bids: Vec<BookLevel>,
+    pub asks: Vec<BookLevel>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Open, high, low and close rate and base currency volume of one candle. A candle without
+/// fills has the previous close as all four rates and zero volume.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Candle {
+    pub open_time: DateTime<Utc>,
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    pub volume: Decimal,
+}
+
+/// A fill in the public trade feed. `order_type` is the taker's side, as in Coincheck.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublicTrade {
+    pub id: Uuid,
     pub pair: String,
     pub rate: Decimal,
     pub amount: Decimal,
-    pub buy_fee: Decimal,
-    pub sell_fee: Decimal,
+    pub order_type: Side,
     pub created_at: DateTime<Utc>,
 }
 
+/// A fill from the point of view of one of its orders, as in Coincheck's transactions API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transaction {
+    pub id: Uuid,
+    pub order_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    /// Balance change per lowercase currency, negative for the currency paid
+    pub funds: HashMap<String, Decimal>,
+    pub pair: String,
+    pub rate: Decimal,
+    pub fee_currency: String,
+    pub fee: Decimal,
+    /// `T` for taker, `M` for maker
+    pub liquidity: String,
+    pub side: Side,
+}
+
+impl Transaction {
+    /// Describes a fill from the maker's or the taker's side. Fees are charged in the currency received.
+    pub fn from_trade(trade: &TradeModel, pair: &PairModel, is_taker: bool) -> Self {
+        let taker_side: Side = trade
+            .taker_side
+            .parse()
+            .expect("taker_side is constrained by the trades table");
+        let (side, order_id, fee) = match (is_taker, taker_side) {
+            (true, side) => (side, trade.taker_order_id, trade.taker_fee),
+            (false, Side::Buy) => (Side::Sell, trade.maker_order_id, trade.maker_fee),
+            (false, Side::Sell) => (Side::Buy, trade.maker_order_id, trade.maker_fee),
+        };
+
+        let base = pair.base_currency.to_lowercase();
+        let quote = pair.quote_currency.to_lowercase();
+        let total = trade.amount * trade.rate;
+        let (funds, fee_currency) = match side {
+            Side::Buy => (
+                HashMap::from([(base, trade.amount - fee), (quote, -total)]),
+                pair.base_currency.clone(),
+            ),
+            Side::Sell => (
+                HashMap::from([(base, -trade.amount), (quote, total - fee)]),
+                pair.quote_currency.clone(),
+            ),
+        };
+
+        Self {
+            id: trade.id,
+            order_id,
+            created_at: trade.created_at,
+            funds,
+            pair: trade.pair.clone(),
+            rate: trade.rate,
+            fee_currency,
+            fee,
+            liquidity: if is_taker { "T" } else { "M" }.to_string(),
+            side,
+        }
+    }
+}
+
+/// Published by settlement to `order-events` after each committed change, keyed by user so every
+/// user's events stay in order. Pushed to the owner over the private WebSocket.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event")]
+pub enum OrderEvent {
+    /// The order's status or remaining amount changed
+    #[serde(rename = "order")]
+    Order {
+        user_id: String,
+        #[serde(flatten)]
+        order: Order,
+    },
+    /// One of the order's fills
+    #[serde(rename = "execution")]
+    Execution {
+        user_id: String,
+        client_order_id: Option<String>,
+        #[serde(flatten)]
+        transaction: Transaction,
+    },
+}
+
+impl OrderEvent {
+    pub fn user_id(&self) -> &str {
+        match self {
+            OrderEvent::Order { user_id, .. } | OrderEvent::Execution { user_id, .. } => user_id,
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Balance {
     pub user_id: String,
@@ -77,8 +535,12 @@ pub struct Balance {
     pub locked: Decimal,
 }
 
+/// Coincheck-style balance: for each lowercase currency `c`, `c` is the available amount,
+/// `c_reserved` the amount locked by open orders and `c_total` their sum.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BalanceResponse {
+    pub success: bool,
+    #[serde(flatten)]
     pub balances: HashMap<String, Decimal>,
 }
 
@@ -88,11 +550,98 @@ pub struct OrderMessage {
     pub user_id: String,
     pub pair: String,
     pub order_type: OrderType,
-    pub rate: Decimal,
+    /// `None` for market orders
+    pub rate: Option<Decimal>,
+    /// Base currency amount, or the quote currency amount to spend for `market_buy`
+    pub amount: Decimal,
+    #[serde(default)]
+    pub time_in_force: TimeInForce,
+    #[serde(default)]
+    pub client_order_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CancelOrderMessage {
+    pub order_id: Uuid,
+    pub user_id: String,
+    pub pair: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Command published to the `orders` topic. New orders and cancellations share the
+/// topic so that a cancel is always processed after the order it refers to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command")]
+pub enum OrderCommand {
+    #[serde(rename = "new")]
+    New(OrderMessage),
+    #[serde(rename = "cancel")]
+    Cancel(CancelOrderMessage),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum CancelReason {
+    #[serde(rename = "user_requested")]
+    UserRequested,
+    /// A market order swept the book and found no more liquidity
+    #[serde(rename = "unfilled")]
+    Unfilled,
+    /// The remainder of an immediate-or-cancel order
+    #[serde(rename = "expired")]
+    Expired,
+    /// A fill-or-kill order the book could not fill completely
+    #[serde(rename = "fill_or_kill")]
+    FillOrKill,
+    /// A post-only order that would have matched on arrival
+    #[serde(rename = "post_only")]
+    PostOnly,
+    /// The matcher refused the order, e.g. its pair is not open for trading
+    #[serde(rename = "rejected")]
+    Rejected,
+    /// Self-trade prevention stopped the order from matching another order of its user
+    #[serde(rename = "self_trade")]
+    SelfTrade,
+}
+
+/// Remainder of an order removed from the book without being filled.
+/// `remaining_amount` is in the quote currency for `market_buy` orders.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CancelledOrder {
+    pub order_id: Uuid,
+    pub pair: String,
+    pub remaining_amount: Decimal,
+    pub reason: CancelReason,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Part of an order removed by self-trade prevention while the rest stays open. `amount` and
+/// `remaining_amount` are in the quote currency for `market_buy` orders.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DecrementedOrder {
+    /// Derived from the taker order like a trade id
+    pub id: Uuid,
+    pub order_id: Uuid,
+    pub pair: String,
+    /// Amount taken off the order
     pub amount: Decimal,
+    /// Unfilled amount left afterwards
+    pub remaining_amount: Decimal,
     pub created_at: DateTime<Utc>,
 }
 
+/// Event published by the matcher to the `matched-orders` topic.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event")]
+pub enum MatcherEvent {
+    #[serde(rename = "matched")]
+    Matched(MatchedOrder),
+    #[serde(rename = "cancelled")]
+    Cancelled(CancelledOrder),
+    #[serde(rename = "decremented")]
+    Decremented(DecrementedOrder),
+}
+
 impl OrderMessage {
     pub fn to_json(&self) -> Result<String, serde_json::Error> {
         serde_json::to_string(self)
@@ -103,6 +652,26 @@ impl OrderMessage {
     }
 }
 
+impl OrderBookSnapshot {
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
+
+impl OrderBookDelta {
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
+
 impl MatchedOrder {
     pub fn to_json(&self) -> Result<String, serde_json::Error> {
         serde_json::to_string(self)
@@ -113,3 +682,38 @@ impl MatchedOrder {
     }
 }
 
+
+impl OrderCommand {
+    pub fn pair(&self) -> &str {
+        match self {
+            OrderCommand::New(order) => &order.pair,
+            OrderCommand::Cancel(cancel) => &cancel.pair,
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
+
+impl MatcherEvent {
+    pub fn pair(&self) -> &str {
+        match self {
+            MatcherEvent::Matched(matched) => &matched.pair,
+            MatcherEvent::Cancelled(cancelled) => &cancelled.pair,
+            MatcherEvent::Decremented(decremented) => &decremented.pair,
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
@@ -0,0 +1,43 @@
+use std::collections::HashMap;
+use sea_orm::{DatabaseConnection, DbErr, EntityTrait};
+
+use crate::entity::{Pair, PairModel};
+
+/// Trading pairs loaded from the `pairs` table at startup.
+#[derive(Debug, Clone, Default)]
+pub struct PairRegistry {
+    pairs: HashMap<String, PairModel>,
+}
+
+impl PairRegistry {
+    pub async fn load(db: &DatabaseConnection) -> Result<Self, DbErr> {
+        let pairs = Pair::find()
+            .all(db)
+            .await?
+            .into_iter()
+            .map(|p| (p.pair.clone(), p))
+            .collect();
+
+        Ok(Self { pairs })
+    }
+
+    pub fn get(&self, pair: &str) -> Option<&PairModel> {
+        self.pairs.get(pair)
+    }
+
+    /// Returns the pair only if it is open for trading.
+    pub fn active(&self, pair: &str) -> Option<&PairModel> {
+        self.get(pair).filter(|p| p.is_active())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PairModel> {
+        self.pairs.values()
+    }
+}
+
+impl FromIterator<PairModel> for PairRegistry {
+    fn from_iter<I: IntoIterator<Item = PairModel>>(pairs: I) -> Self {
+        let pairs = pairs.into_iter().map(|p| (p.pair.clone(), p)).collect();
+        Self { pairs }
+    }
+}